@@ -1,20 +1,39 @@
 #![allow(unknown_lints)]
 
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{self, ErrorKind, Write};
+use std::io::{self, BufRead, BufReader, ErrorKind, IsTerminal, Write};
+use std::iter::Peekable;
 use std::mem::take;
+use std::net::ToSocketAddrs;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context, Result};
+use cargo_metadata::camino::Utf8PathBuf;
 use cargo_metadata::{
-    DepKindInfo, DependencyKind, MetadataCommand, Node, Package, PackageId, Resolve,
+    DepKindInfo, DependencyKind, MetadataCommand, Node, Package, PackageId, Resolve, Source,
 };
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+mod xlsx;
 
 const DEST_FILENAME: &str = "LICENSE-3rdparty.csv";
 
@@ -22,6 +41,46 @@ const CONFIG_FILENAME: &str = "license-tool.toml";
 
 const COPYRIGHT_KEY: &str = "__COPYRIGHT__";
 
+const RELATION_KEY: &str = "__RELATION__";
+
+const DEPENDENTS_KEY: &str = "__DEPENDENTS__";
+
+const OVERRIDE_KEY: &str = "__OVERRIDE__";
+
+const INDEX_NAME_KEY: &str = "__INDEX_NAME__";
+
+// The columns every license file has, unlike the optional columns.
+const REQUIRED_COLUMNS: [&str; 3] = ["Component", "Origin", "License"];
+
+const DEFAULT_COPYRIGHT_SEPARATOR: &str = ", ";
+
+// The patterns of repository names for their main component, used when `name_reduction_patterns`
+// is not configured.
+static DEFAULT_NAME_REDUCTION_PATTERNS: [NamePattern; 2] =
+    [NamePattern::new("rust-", ""), NamePattern::new("", "-rs")];
+
+// The default maximum length of the descriptions in the `Description` column.
+const DEFAULT_MAX_DESCRIPTION_LENGTH: usize = 200;
+
+// The default minimum length of a scanned copyright after the leading `copyright`, below which it
+// holds no more than a year or two and is skipped.
+const DEFAULT_MIN_COPYRIGHT_LENGTH: usize = 10;
+
+// The index URLs of crates.io, using either the git or the sparse protocol.
+const CRATES_IO_INDEXES: [&str; 2] = [
+    "https://github.com/rust-lang/crates.io-index",
+    "https://index.crates.io",
+];
+
+// The machine-readable Debian copyright file, which lists the copyright holders directly.
+const DEBIAN_COPYRIGHT: &str = "debian/copyright";
+
+// The start of the comment line at the end of the license file holding the hash of the records.
+const HASH_PREFIX: &str = "# sha256: ";
+
+// Directories that are not searched for projects by `--scan-root`.
+const SKIPPED_DIRECTORIES: [&str; 3] = [".git", "target", "vendor"];
+
 // Files searched for copyright notices
 const COPYRIGHT_LOCATIONS: [&str; 17] = [
     "license",
@@ -58,24 +117,568 @@ static RE_COPYRIGHT_IGNORE: Lazy<Regex> = Lazy::new(|| {
     r"(?i)^(copyright(:? and license)?$|copyright (:?holder|owner|notice|license|statement)|Copyright & License -|copyright .yyyy. .name of copyright owner)").unwrap()
 });
 
+// A reference to an environment variable in a configuration value, like `${NAME}` or
+// `${NAME:-default}`.
+static RE_ENV_VAR: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap());
+
+// The license identifiers and operators in a license expression.
+static RE_LICENSE_TOKEN: Lazy<Regex> = Lazy::new(|| Regex::new(r"[^\s()]+").unwrap());
+
+thread_local! {
+    // The prefix added to warnings, identifying the project being processed on this thread.
+    static WARNING_PREFIX: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// The number of warnings of each kind printed since they were last checked, which `--strict` turns
+// into a failure.
+static WARNINGS: Mutex<BTreeMap<Warning, usize>> = Mutex::new(BTreeMap::new());
+
+// Print a warning of the given kind to standard error, prefixed with the project it came from when
+// processing several projects at once, and count it for `--strict`.
+macro_rules! warn {
+    ($kind:expr, $($arg:tt)*) => {{
+        *WARNINGS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry($kind)
+            .or_default() += 1;
+        report!($($arg)*)
+    }};
+}
+
+// Print the details of an error to standard error, prefixed like warnings but not counted as one.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        WARNING_PREFIX.with_borrow(|prefix| eprintln!("{prefix}{}", format_args!($($arg)*)))
+    };
+}
+
+fn set_warning_prefix(manifest_path: Option<&Path>) {
+    let prefix = manifest_path.map_or_else(String::new, |path| format!("{}: ", path.display()));
+    WARNING_PREFIX.set(prefix);
+}
+
 #[derive(Debug, Parser)]
 struct Args {
-    /// Load a configuration file containing package overrides. Defaults to "license-tool.toml".
-    #[arg(short, long, value_name = "FILENAME")]
+    /// Load a configuration file containing package overrides, or the "license-tool.toml" in the
+    /// given directory. Defaults to "license-tool.toml".
+    #[arg(
+        short,
+        long,
+        env = "LICENSE_TOOL_CONFIG",
+        value_name = "FILENAME",
+        value_hint = ValueHint::AnyPath
+    )]
     config: Option<PathBuf>,
 
+    /// The license file to write or check. Defaults to the `output` configuration setting, or
+    /// "LICENSE-3rdparty.csv".
+    #[arg(short, long, value_name = "FILENAME", value_hint = ValueHint::FilePath)]
+    output: Option<PathBuf>,
+
+    /// Write a JSON file with the provenance of the license file, including a hash of `Cargo.lock`,
+    /// when writing, and check that the lockfile hash is current when checking.
+    #[arg(long, value_name = "FILENAME", value_hint = ValueHint::FilePath)]
+    metadata_file: Option<PathBuf>,
+
+    /// Reuse the copyrights in the existing license file for the packages that are unchanged since
+    /// it was written, according to the `--metadata-file`, and only scan the new or changed
+    /// packages. Any change to the configuration or options scans every package again.
+    #[arg(long, requires = "metadata_file")]
+    incremental: bool,
+
+    /// Import the license clarifications from a cargo-about configuration file as overrides.
+    /// Defaults to the `import_about` configuration setting.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    import_about: Option<PathBuf>,
+
+    /// Validate the configuration file and exit without generating the license data or running
+    /// `cargo metadata`.
+    #[arg(long)]
+    config_check: bool,
+
+    /// With `--config-check`, also validate the overrides in the manifests of the workspace
+    /// members, which runs `cargo metadata --no-deps` to find them.
+    #[arg(long, requires = "config_check")]
+    check_manifests: bool,
+
+    /// Path to the `Cargo.toml` or directory of the project to process. This may be repeated to
+    /// merge the license data of several projects. Defaults to the project in the current
+    /// directory.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    manifest_path: Vec<PathBuf>,
+
+    /// Process every project found under this directory, merging their license data. Build
+    /// output, `.git`, and vendored directories are skipped, as are directories within a project.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with = "manifest_path")]
+    scan_root: Option<PathBuf>,
+
+    /// The directory that paths in messages are shown relative to. Defaults to the workspace root
+    /// reported by `cargo metadata`.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::DirPath)]
+    workspace_root: Option<PathBuf>,
+
+    /// The maximum number of threads used to process projects and scan for copyrights. Defaults to
+    /// the `CARGO_BUILD_JOBS` environment variable, or the number of CPUs.
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+
+    /// The `cargo` binary used to run `cargo metadata`. Defaults to the `CARGO` environment
+    /// variable, or `cargo` from the `PATH`.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::ExecutablePath)]
+    cargo_path: Option<PathBuf>,
+
+    /// An extra argument to pass to `cargo metadata`, such as `--cargo-arg=--offline`. This may be
+    /// repeated, and the arguments are passed in order without validation, so arguments that
+    /// change the output format will break parsing of the metadata.
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    cargo_arg: Vec<String>,
+
+    /// Print the `cargo metadata` command that would be run, and exit without running it.
+    #[arg(long)]
+    emit_cargo_metadata_cmd: bool,
+
+    /// Add a `Relation` column marking each dependency as direct or transitive.
+    #[arg(long)]
+    include_relation: bool,
+
+    /// Add a `Group` column listing the configured dependency groups of each package.
+    #[arg(long)]
+    include_groups: bool,
+
+    /// Keep the annotation columns of the existing license file, whose headers start with `#`, when
+    /// writing it, and ignore them when checking it.
+    #[arg(long)]
+    preserve_annotations: bool,
+
+    /// Add a `Dependents` column listing the workspace members that depend on each package,
+    /// directly or transitively.
+    #[arg(long)]
+    annotate_dependents: bool,
+
+    /// Add a `Download` column with the URL of the exact artifact of each package, for crates.io,
+    /// git, and configured registry sources.
+    #[arg(long)]
+    include_download: bool,
+
+    /// Add an `IndexName` column with the name of each package in the registry index and
+    /// `Cargo.lock`, when its manifest gives it a different name.
+    #[arg(long)]
+    include_index_name: bool,
+
+    /// Add a `Notes` column with the text configured for each component in the `[annotations]`
+    /// table.
+    #[arg(long)]
+    include_notes: bool,
+
+    /// Add a `Description` column with the description of each package from its manifest.
+    #[arg(long)]
+    include_description: bool,
+
+    /// Only include packages from these kinds of sources, given as a comma-separated list or by
+    /// repeating the option. Defaults to all sources.
+    #[arg(long, value_name = "KIND", value_delimiter = ',')]
+    source_filter: Vec<SourceKind>,
+
+    /// Fail if any dependency is from these kinds of sources, listing the packages and how they are
+    /// reached from the workspace.
+    #[arg(long, value_name = "KIND", value_delimiter = ',')]
+    deny_source: Vec<SourceKind>,
+
+    /// Include the workspace member packages, with their origin set to the `workspace_origin`
+    /// configuration setting or the repository of the root package.
+    #[arg(long)]
+    include_workspace_members: bool,
+
+    /// Append a comment line with the SHA-256 hash of the records to the license file, and verify
+    /// it when checking.
+    #[arg(long)]
+    include_hash: bool,
+
+    /// Keep the records in the order the packages appear in `cargo metadata`, instead of sorting.
+    #[arg(long)]
+    no_sort: bool,
+
+    /// Sort the records by license, and separate each license in the `dump` output with a header.
+    #[arg(long, conflicts_with = "no_sort")]
+    group_by_license: bool,
+
+    /// Name components from git sources with a tag as `<name>@<tag>`.
+    #[arg(long)]
+    git_tag_as_version: bool,
+
+    /// Leave out the `Copyright` column, skipping the search for copyrights.
+    #[arg(long)]
+    no_copyright: bool,
+
+    /// Use this regular expression to find copyright lines, instead of the built-in one. Defaults
+    /// to the `copyright_regex` configuration setting.
+    #[arg(long, value_name = "PATTERN", value_parser = Regex::new)]
+    copyright_regex: Option<Regex>,
+
+    /// Ignore copyright lines matching this regular expression, instead of the built-in one.
+    /// Defaults to the `copyright_ignore_regex` configuration setting.
+    #[arg(long, value_name = "PATTERN", value_parser = Regex::new)]
+    copyright_ignore_regex: Option<Regex>,
+
+    /// Only read this many lines from the start of each file when searching for copyright lines.
+    #[arg(long, value_name = "N", default_value_t = 500)]
+    copyright_scan_lines: usize,
+
+    /// Only include dependencies up to N levels away from the workspace, where 1 is only direct
+    /// dependencies. The result is not a complete attribution, so this cannot be used with `check`.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    depth: Option<u32>,
+
+    /// Only include dependencies up to N levels below the direct dependencies, where 0 is only
+    /// direct dependencies. This is the same as `--depth` with one more level.
+    #[arg(long, value_name = "N", conflicts_with = "depth")]
+    max_depth: Option<u32>,
+
+    /// Write a JSON file listing each override applied, with the fields it set and the values they
+    /// replaced.
+    #[arg(long, value_name = "FILENAME", value_hint = ValueHint::FilePath)]
+    override_log: Option<PathBuf>,
+
+    /// Write the graph of the normal dependencies included in Graphviz DOT format, highlighting
+    /// the packages in the license data and the workspace members.
+    #[arg(long, value_name = "FILENAME", value_hint = ValueHint::FilePath)]
+    emit_dot: Option<PathBuf>,
+
+    /// Write a Makefile rule making the license file depend on the `Cargo.lock`, the workspace
+    /// manifests, the configuration file, and the files scanned for copyrights.
+    #[arg(long, value_name = "FILENAME", value_hint = ValueHint::FilePath)]
+    emit_make_deps: Option<PathBuf>,
+
+    /// Print statistics about the run to standard error.
+    #[arg(long)]
+    stats: bool,
+
+    /// Print more details about the run to standard error, including the statistics.
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Do not print the summary of the records to standard error after writing the license file.
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Fail if any warnings are printed, listing the number of each kind at the end.
+    #[arg(long)]
+    strict: bool,
+
+    /// Do not fail on this kind of warning with `--strict`. This may be repeated.
+    #[arg(long, value_name = "WARNING")]
+    allow: Vec<Warning>,
+
+    /// Look up the host name of each origin URL in DNS, reporting those that do not resolve.
+    #[arg(long)]
+    validate_urls: bool,
+
+    /// Warn about dependencies whose licenses are not compatible with the license of the root
+    /// package of the workspace.
+    #[arg(long)]
+    check_spdx_compatibility: bool,
+
+    /// Treat records with the same component name and origin as an error, instead of a warning.
+    #[arg(long)]
+    error_on_duplicate_origin: bool,
+
+    /// Fail if any override in the configuration file does not apply to a package, such as one left
+    /// behind for a removed dependency.
+    #[arg(long)]
+    require_all_overrides_matched: bool,
+
+    /// Print a report about the license data to standard error. This may be repeated.
+    #[arg(long, value_name = "REPORT")]
+    report: Vec<Report>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Args {
+    // Resolve any directories given for the manifest or configuration paths to the file of the
+    // expected name within them.
+    fn resolve_directories(&mut self) -> Result<()> {
+        let paths = self
+            .manifest_path
+            .iter_mut()
+            .map(|path| (path, "Cargo.toml"))
+            .chain(self.config.as_mut().map(|path| (path, CONFIG_FILENAME)));
+        for (path, filename) in paths {
+            if path.is_dir() {
+                path.push(filename);
+                if !path.is_file() {
+                    bail!(
+                        "Could not find {filename:?} in {:?}",
+                        path.parent().unwrap()
+                    );
+                }
+            }
+        }
+        if let Some(root) = &self.scan_root {
+            find_projects(root, &mut self.manifest_path)?;
+            if self.manifest_path.is_empty() {
+                bail!("Could not find any projects under {root:?}");
+            }
+        }
+        Ok(())
+    }
+
+    // The manifests of the projects to process, where `None` is the project in the current
+    // directory.
+    fn manifest_paths(&self) -> Vec<Option<&Path>> {
+        if self.manifest_path.is_empty() {
+            vec![None]
+        } else {
+            self.manifest_path
+                .iter()
+                .map(|path| Some(path.as_path()))
+                .collect()
+        }
+    }
+
+    // The number of threads to use, which falls back to `CARGO_BUILD_JOBS` like cargo itself.
+    fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| env::var("CARGO_BUILD_JOBS").ok()?.parse().ok())
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+
+    fn config_filename(&self) -> &Path {
+        self.config
+            .as_deref()
+            .unwrap_or_else(|| Path::new(CONFIG_FILENAME))
+    }
+
+    // The maximum depth of dependencies to include, counting direct dependencies as depth 1.
+    fn depth_limit(&self) -> Option<u32> {
+        self.depth
+            .or(self.max_depth.map(|depth| depth.saturating_add(1)))
+    }
+}
+
+// The reports about the license data that can be printed with `--report`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum Report {
+    /// Components whose license expression requires all of several licenses, with a top-level
+    /// `AND`, or adds an exception with `WITH`.
+    And,
+    /// The number of components in each license category, listing those that are not permissive.
+    Categories,
+}
+
+// The kinds of warnings, which `--strict` turns into errors unless allowed with `--allow`.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, ValueEnum)]
+enum Warning {
+    /// Different records with the same component name.
+    Collision,
+    /// Several records with the same component name and origin.
+    DuplicateOrigin,
+    /// Origin URLs whose host does not resolve, with `--validate-urls`.
+    UnresolvedHost,
+    /// Packages imported from an SBOM with an invalid origin URL.
+    InvalidOrigin,
+    /// Packages using their crates.io page as their origin, with `fallback_origin_cratesio`, or
+    /// the URL of their alternative registry.
+    FallbackOrigin,
+    /// Packages without a license, with the `warn` or `skip` missing license behavior.
+    MissingLicense,
+    /// Packages from a registry without a configured download URL, with `--include-download`.
+    MissingDownload,
+    /// Annotations for components that are not in the license data.
+    UnmatchedAnnotation,
+    /// Symlinked license files that are broken or point outside of the package.
+    Symlink,
+    /// Unknown columns in the existing license file.
+    UnknownColumn,
+    /// Changes to the license data allowed by `check --tolerate`.
+    ToleratedChange,
+    /// Dependency licenses incompatible with the project license, with
+    /// `--check-spdx-compatibility`.
+    IncompatibleLicense,
+}
+
+impl Warning {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Collision => "collision",
+            Self::DuplicateOrigin => "duplicate-origin",
+            Self::UnresolvedHost => "unresolved-host",
+            Self::InvalidOrigin => "invalid-origin",
+            Self::FallbackOrigin => "fallback-origin",
+            Self::MissingLicense => "missing-license",
+            Self::MissingDownload => "missing-download",
+            Self::UnmatchedAnnotation => "unmatched-annotation",
+            Self::Symlink => "symlink",
+            Self::UnknownColumn => "unknown-column",
+            Self::ToleratedChange => "tolerated-change",
+            Self::IncompatibleLicense => "incompatible-license",
+        }
+    }
+}
+
+// The encodings the license file can be written in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum OutputEncoding {
+    #[value(name = "utf-8")]
+    Utf8,
+    #[value(name = "utf-8-bom")]
+    Utf8Bom,
+}
+
+// The formats the license file can be written in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Csv,
+    Xlsx,
+}
+
+// The formats the `stats` subcommand prints in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum StatsFormat {
+    Text,
+    Json,
+}
+
+// The number of entries listed for each ranking of the `stats` subcommand.
+const STATS_TOP_COUNT: usize = 10;
+
+// The byte order mark written at the start of a UTF-8 file for `--output-encoding utf-8-bom`.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// The kinds of sources packages come from, for `--source-filter` and `--deny-source`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum SourceKind {
+    /// Packages from crates.io or another registry.
+    Registry,
+    /// Packages from git repositories.
+    Git,
+    /// Local packages, which are only included in the output with `--include-workspace-members`.
+    Path,
+    /// Packages from any source.
+    All,
+}
+
+impl SourceKind {
+    fn of(package: &Package) -> Self {
+        match &package.source {
+            None => Self::Path,
+            Some(source) if source.repr.starts_with("git+") => Self::Git,
+            Some(_) => Self::Registry,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Registry => "registry",
+            Self::Git => "git",
+            Self::Path => "path",
+            Self::All => "any",
+        }
+    }
+
+    // Check if the source of a package is one of the kinds.
+    fn matches(kinds: &[Self], package: &Package) -> bool {
+        let kind = Self::of(package);
+        kinds
+            .iter()
+            .any(|&other| other == Self::All || other == kind)
+    }
+}
+
+// The kinds of changes to the license data that `check --tolerate` allows.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum Tolerate {
+    /// Records generated for new dependencies that are missing from the file.
+    Additions,
+    /// Records in the file for dependencies that were removed.
+    Removals,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Dump the generated license data to standard output.
     Dump,
     /// Write the generated license data to the file.
-    Write,
+    Write {
+        /// Write the file even if its contents would not change.
+        #[arg(long)]
+        force: bool,
+        /// Show the changes that would be made without writing anything, exiting with status 2 if
+        /// there are any.
+        #[arg(long, conflicts_with = "force")]
+        dry_run: bool,
+        /// The encoding of the file written, where `utf-8-bom` starts it with a byte order mark so
+        /// that Excel reads it as UTF-8.
+        #[arg(long, value_name = "ENCODING", default_value = "utf-8")]
+        output_encoding: OutputEncoding,
+        /// The format of the file written, where `xlsx` writes an Excel workbook with a second
+        /// sheet counting the components of each license. This needs `--output` to name the file.
+        #[arg(long, default_value = "csv", conflicts_with = "dry_run")]
+        format: OutputFormat,
+        /// Keep the rows of the existing file for components that are not generated, such as
+        /// hand-added ones for components outside of Rust. Defaults to the `preserve_extra`
+        /// configuration setting, which also makes `check` accept these rows.
+        #[arg(long, conflicts_with = "format")]
+        preserve_extra: bool,
+    },
     /// Check that the license data is up to date.
-    Check,
+    Check {
+        /// Write the updated license data if the check fails, still exiting with an error.
+        #[arg(long)]
+        update_on_fail: bool,
+        /// Compare against the license file as it was at this git revision instead, listing the
+        /// changes since then.
+        #[arg(long, value_name = "REF", conflicts_with = "update_on_fail")]
+        diff_against: Option<String>,
+        /// Compare against this license file instead, listing the changes from it.
+        #[arg(
+            long,
+            value_name = "FILENAME",
+            value_hint = ValueHint::FilePath,
+            conflicts_with_all = ["update_on_fail", "diff_against"]
+        )]
+        diff_base: Option<PathBuf>,
+        /// Only warn about this kind of change, while the other changes still fail the check.
+        #[arg(long, value_name = "CHANGES", conflicts_with_all = ["diff_against", "diff_base"])]
+        tolerate: Option<Tolerate>,
+    },
+    /// Write the license file from the packages in an SPDX 2.3 or CycloneDX 1.4 JSON SBOM, instead
+    /// of generating the license data.
+    FromSbom {
+        /// The SBOM file to import.
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    /// Print the most common licenses and origin domains, the number of components in each license
+    /// category, and how the copyrights were found.
+    Stats {
+        /// Read the records from the existing license file instead of generating them, which leaves
+        /// out how the copyrights were found.
+        #[arg(long)]
+        existing: bool,
+        /// The format of the output.
+        #[arg(long, default_value = "text")]
+        format: StatsFormat,
+    },
+    /// Install a git pre-commit hook that runs `check` with the same options, blocking commits
+    /// while the license data is out of date.
+    InstallHook {
+        /// Replace an existing pre-commit hook.
+        #[arg(long)]
+        force: bool,
+        /// Print the hook to standard output instead of installing it.
+        #[arg(long, conflicts_with = "force")]
+        dry_run: bool,
+    },
+    /// Print the shell completion script for this tool to standard output.
+    Completions {
+        /// The shell to generate the completions for.
+        shell: Shell,
+    },
 }
 
 #[derive(Deserialize)]
@@ -89,8 +692,208 @@ struct ManifestPackage {
 }
 
 #[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 struct Config {
     overrides: Overrides,
+    output: Option<PathBuf>,
+    copyright_source: CopyrightSource,
+    copyright_priority: Option<Vec<CopyrightClass>>,
+    dependency_groups: DependencyGroups,
+    license_categories: LicenseCategories,
+    aliases: BTreeMap<String, String>,
+    // Maps component names to the text of their `Notes` column.
+    annotations: BTreeMap<String, String>,
+    registry_downloads: RegistryDownloads,
+    registry_names: RegistryNames,
+    missing_license_behavior: MissingLicenseBehavior,
+    max_copyright_length: Option<usize>,
+    copyright_min_length: Option<usize>,
+    copyright_regex: Option<ConfigRegex>,
+    copyright_ignore_regex: Option<ConfigRegex>,
+    max_description_length: Option<usize>,
+    copyright_separator: Option<String>,
+    headers: Headers,
+    copyright_encoding: CopyrightEncoding,
+    no_copyright: bool,
+    multiline: bool,
+    max_copyright_lines: Option<NonZeroUsize>,
+    import_about: Option<PathBuf>,
+    workspace_origin: Option<String>,
+    license_expression_normalize_or: bool,
+    authors_join_strategy: AuthorsJoinStrategy,
+    fallback_origin_cratesio: bool,
+    strict: bool,
+    preserve_extra: bool,
+    scan_vendored: bool,
+    // The packages to scan for vendored code, defaulting to all of them.
+    scan_vendored_crates: Option<Vec<String>>,
+    vendored_dirs: Option<Vec<String>>,
+    name_reduction_patterns: Option<Vec<NamePattern>>,
+}
+
+// The broad categories of licenses, from the least to the most restrictive.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
+enum LicenseCategory {
+    Permissive,
+    WeakCopyleft,
+    StrongCopyleft,
+    Other,
+    Unknown,
+}
+
+impl LicenseCategory {
+    const ALL: [Self; 5] = [
+        Self::Permissive,
+        Self::WeakCopyleft,
+        Self::StrongCopyleft,
+        Self::Other,
+        Self::Unknown,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Permissive => "permissive",
+            Self::WeakCopyleft => "weak-copyleft",
+            Self::StrongCopyleft => "strong-copyleft",
+            Self::Other => "other",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    // The built-in category of an SPDX license identifier, if it is a well-known one.
+    fn builtin(id: &str) -> Option<Self> {
+        let id = strip_suffix(id, "+");
+        match id {
+            "0BSD" | "Apache-2.0" | "BlueOak-1.0.0" | "BSD-1-Clause" | "BSD-2-Clause"
+            | "BSD-3-Clause" | "BSL-1.0" | "CC0-1.0" | "ISC" | "MIT" | "MIT-0" | "NCSA"
+            | "OpenSSL" | "Unicode-3.0" | "Unicode-DFS-2016" | "Unlicense" | "WTFPL" | "X11"
+            | "Zlib" => Some(Self::Permissive),
+            "CDDL-1.0" | "CDDL-1.1" | "EPL-1.0" | "EPL-2.0" | "LGPL-2.0" | "LGPL-2.0-only"
+            | "LGPL-2.0-or-later" | "LGPL-2.1" | "LGPL-2.1-only" | "LGPL-2.1-or-later"
+            | "LGPL-3.0" | "LGPL-3.0-only" | "LGPL-3.0-or-later" | "MPL-1.1" | "MPL-2.0" => {
+                Some(Self::WeakCopyleft)
+            }
+            "AGPL-3.0" | "AGPL-3.0-only" | "AGPL-3.0-or-later" | "EUPL-1.2" | "GPL-2.0"
+            | "GPL-2.0-only" | "GPL-2.0-or-later" | "GPL-3.0" | "GPL-3.0-only"
+            | "GPL-3.0-or-later" | "OSL-3.0" => Some(Self::StrongCopyleft),
+            "BUSL-1.1" | "CC-BY-4.0" | "CC-BY-SA-4.0" | "Elastic-2.0" | "SSPL-1.0" => {
+                Some(Self::Other)
+            }
+            _ => None,
+        }
+    }
+}
+
+// What to do with a package that has no license, even after overrides are applied.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum MissingLicenseBehavior {
+    /// Report an error and fail.
+    #[default]
+    Error,
+    /// Report a warning and output an empty license.
+    Warn,
+    /// Report a warning and leave the package out of the output.
+    Skip,
+    /// Output the license as "UNKNOWN".
+    Placeholder,
+}
+
+// The text encoding of the files scanned for copyright lines.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+enum CopyrightEncoding {
+    /// UTF-8, failing on files that are not valid UTF-8.
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    /// ISO-8859-1, decoded as its Windows-1252 superset.
+    #[serde(rename = "latin-1")]
+    Latin1,
+    /// Windows-1252.
+    #[serde(rename = "windows-1252")]
+    Windows1252,
+}
+
+impl CopyrightEncoding {
+    fn decode(self, bytes: Vec<u8>) -> io::Result<String> {
+        match self {
+            Self::Utf8 => String::from_utf8(bytes)
+                .map_err(|error| io::Error::new(ErrorKind::InvalidData, error)),
+            Self::Latin1 | Self::Windows1252 => Ok(encoding_rs::WINDOWS_1252
+                .decode_without_bom_handling(&bytes)
+                .0
+                .into_owned()),
+        }
+    }
+}
+
+// The order in which copyright strategies are tried when building the copyright field.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum CopyrightSource {
+    /// Scan the package files first, falling back to the manifest authors.
+    #[default]
+    Scan,
+    /// Use the manifest authors, falling back to scanning the package files when there are none.
+    AuthorsFirst,
+    /// Only use the manifest authors, never scanning the package files.
+    AuthorsOnly,
+}
+
+// The classes of files scanned for copyrights, by their names, in the order they are scanned by
+// default.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum CopyrightClass {
+    /// `LICENSE` files, in any case and with any extension or suffix.
+    License,
+    /// `COPYING` files.
+    Copying,
+    /// `NOTICE` files.
+    Notice,
+    /// `COPYRIGHT` files.
+    Copyright,
+    /// `README` files.
+    Readme,
+}
+
+const DEFAULT_COPYRIGHT_PRIORITY: [CopyrightClass; 5] = [
+    CopyrightClass::License,
+    CopyrightClass::Copying,
+    CopyrightClass::Notice,
+    CopyrightClass::Copyright,
+    CopyrightClass::Readme,
+];
+
+impl CopyrightClass {
+    fn of(location: &str) -> Self {
+        let name = location.to_ascii_uppercase();
+        if name.starts_with("LICENSE") {
+            Self::License
+        } else if name.starts_with("COPYING") {
+            Self::Copying
+        } else if name.starts_with("NOTICE") {
+            Self::Notice
+        } else if name.starts_with("COPYRIGHT") {
+            Self::Copyright
+        } else {
+            Self::Readme
+        }
+    }
+}
+
+// How the manifest authors are combined into a copyright.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum AuthorsJoinStrategy {
+    /// List all of the authors.
+    #[default]
+    All,
+    /// Use only the first author.
+    First,
+    /// Use the number of authors, as "N contributors", when there is more than one.
+    Count,
 }
 
 #[derive(Clone, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
@@ -99,30 +902,212 @@ struct Record {
     component: String,
     origin: String,
     license: String,
-    copyright: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    copyright: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relation: Option<Relation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dependents: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notes: Option<String>,
+}
+
+impl Record {
+    // The names and values of the fields of this record, as they are output.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("Component", self.component.clone()),
+            ("Origin", self.origin.clone()),
+            ("License", self.license.clone()),
+        ];
+        if let Some(copyright) = &self.copyright {
+            fields.push(("Copyright", copyright.clone()));
+        }
+        if let Some(relation) = self.relation {
+            fields.push(("Relation", relation.as_str().into()));
+        }
+        if let Some(group) = &self.group {
+            fields.push(("Group", group.clone()));
+        }
+        if let Some(dependents) = &self.dependents {
+            fields.push(("Dependents", dependents.clone()));
+        }
+        if let Some(description) = &self.description {
+            fields.push(("Description", description.clone()));
+        }
+        if let Some(download) = &self.download {
+            fields.push(("Download", download.clone()));
+        }
+        if let Some(index_name) = &self.index_name {
+            fields.push(("IndexName", index_name.clone()));
+        }
+        if let Some(notes) = &self.notes {
+            fields.push(("Notes", notes.clone()));
+        }
+        fields
+    }
+
+    // List the fields that differ between this record and another, as the field name and the
+    // values from both records, with missing fields shown as empty.
+    fn changed_fields(&self, other: &Record) -> Vec<(&'static str, String, String)> {
+        let mut fields: Vec<(&str, String, String)> = self
+            .fields()
+            .into_iter()
+            .map(|(name, value)| (name, value, String::new()))
+            .collect();
+        for (name, value) in other.fields() {
+            match fields.iter_mut().find(|field| field.0 == name) {
+                Some(field) => field.2 = value,
+                None => fields.push((name, String::new(), value)),
+            }
+        }
+        fields.retain(|(_, from, to)| from != to);
+        fields
+    }
+}
+
+// How a package is reached from the workspace. `Direct` sorts first so that it wins when a package
+// is reached both ways.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Relation {
+    Direct,
+    Transitive,
+}
+
+impl Relation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Direct => "direct",
+            Self::Transitive => "transitive",
+        }
+    }
 }
 
 impl Config {
     fn load(filename: &Path) -> Result<Option<Self>> {
         match fs::read_to_string(filename) {
-            Ok(text) => {
+            // Parsing the text directly keeps the locations in any error messages.
+            Ok(text) if !text.contains("${") => {
                 toml::from_str(&text).with_context(|| format!("Could not parse {filename:?}"))
             }
+            Ok(text) => {
+                let mut value: toml::Value = toml::from_str(&text)
+                    .with_context(|| format!("Could not parse {filename:?}"))?;
+                substitute_env_value(&mut value, "")
+                    .with_context(|| format!("Could not load from {filename:?}"))?;
+                value
+                    .try_into()
+                    .with_context(|| format!("Could not parse {filename:?}"))
+            }
             Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
             Err(error) => Err(error).with_context(|| format!("Could not load from {filename:?}")),
         }
     }
+
+    // Add the license clarifications from a cargo-about configuration to the overrides. Licenses
+    // already set by the overrides take precedence.
+    fn import_about(&mut self, filename: &Path) -> Result<()> {
+        let text = fs::read_to_string(filename)
+            .with_context(|| format!("Could not load from {filename:?}"))?;
+        let about: toml::Table =
+            toml::from_str(&text).with_context(|| format!("Could not parse {filename:?}"))?;
+        let mut imported = 0;
+        let mut shadowed = Vec::new();
+        for (name, value) in about {
+            let Some(license) = value
+                .get("clarify")
+                .and_then(|clarify| clarify.get("license"))
+                .and_then(toml::Value::as_str)
+            else {
+                continue;
+            };
+            let opts = self.overrides.entry(name.clone()).or_default();
+            if opts.license.is_some() {
+                shadowed.push(name);
+            } else {
+                opts.license = Some(license.into());
+                imported += 1;
+            }
+        }
+        eprintln!("Imported {imported} license clarifications from {filename:?}.");
+        if !shadowed.is_empty() {
+            eprintln!(
+                "Kept the configured overrides instead of the clarifications for {}.",
+                shadowed.join(", ")
+            );
+        }
+        Ok(())
+    }
 }
 
 type Overrides = HashMap<String, Override>;
 
-#[derive(Deserialize)]
+// Maps license identifiers to their categories, in addition to the built-in ones.
+type LicenseCategories = BTreeMap<String, LicenseCategory>;
+
+// Maps registry index URLs to the template of the download URL of their crates.
+type RegistryDownloads = BTreeMap<String, String>;
+
+// Maps registry index URLs to the names they are shown with in messages.
+type RegistryNames = BTreeMap<String, String>;
+
+// Maps group names to the names of the packages in that group.
+type DependencyGroups = BTreeMap<String, Vec<String>>;
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Override {
     license: Option<String>,
     origin: Option<String>,
+    copyright_encoding: Option<CopyrightEncoding>,
 }
 
 impl Override {
+    // Describe the fields this override sets on the package, with the values they replace.
+    fn log_entry(&self, key: &str, package: &Package) -> OverrideLogEntry {
+        let mut fields = BTreeMap::new();
+        if let Some(license) = &self.license {
+            let field = OverriddenField {
+                value: license.clone(),
+                replaced: package.license.clone(),
+            };
+            fields.insert("license".into(), field);
+        }
+        if let Some(origin) = &self.origin {
+            let field = OverriddenField {
+                value: origin.clone(),
+                replaced: package.repository.clone(),
+            };
+            fields.insert("origin".into(), field);
+        }
+        if let Some(encoding) = self.copyright_encoding {
+            let field = OverriddenField {
+                value: serde_json::to_value(encoding)
+                    .expect("Encodings are serializable")
+                    .as_str()
+                    .expect("Encodings are serialized as strings")
+                    .into(),
+                replaced: None,
+            };
+            fields.insert("copyright_encoding".into(), field);
+        }
+        OverrideLogEntry {
+            key: key.into(),
+            package: package.name.clone(),
+            version: package.version.to_string(),
+            fields,
+        }
+    }
+
     fn fixup(&self, package: &mut Package) {
         if let Some(license) = &self.license {
             package.license = Some(license.to_owned());
@@ -133,352 +1118,4724 @@ impl Override {
     }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    args.command.doit(build_everything(args.config)?)
+// An override applied to a package, as written to the `--override-log` file.
+#[derive(Deserialize, Serialize)]
+struct OverrideLogEntry {
+    key: String,
+    package: String,
+    version: String,
+    fields: BTreeMap<String, OverriddenField>,
 }
 
-impl Commands {
-    fn doit(self, records: Vec<Record>) -> Result<()> {
-        match self {
-            Self::Dump => output_table(records, io::stdout()),
-            Self::Write => Self::write(records),
-            Self::Check => Self::check(records),
-        }
-    }
-
-    fn write(records: Vec<Record>) -> Result<()> {
-        let temp_filename = format!("{DEST_FILENAME}.tmp.{}", std::process::id());
-        let out = File::create(&temp_filename)
-            .with_context(|| format!("Could not create {temp_filename:?}"))?;
-        output_table(records, out)?;
-        fs::rename(&temp_filename, DEST_FILENAME)
-            .with_context(|| format!("Could not rename {temp_filename:?} to {DEST_FILENAME:?}"))
-    }
+#[derive(Deserialize, Serialize)]
+struct OverriddenField {
+    value: String,
+    // The value from the package metadata that the override replaced.
+    replaced: Option<String>,
+}
 
-    fn check(records: Vec<Record>) -> Result<()> {
-        let mut current: HashSet<Record> = match File::open(DEST_FILENAME) {
-            Err(error) if error.kind() == ErrorKind::NotFound => Default::default(),
-            Err(error) => return Err(error).context(format!("Could not read {DEST_FILENAME:?}")),
-            Ok(file) => csv::Reader::from_reader(file)
-                .into_deserialize::<Record>()
-                .collect::<Result<_, _>>()
-                .with_context(|| format!("Could not read current {DEST_FILENAME:?}"))?,
-        };
-        let mut errors = false;
-        for record in records {
-            if !current.remove(&record) {
-                println!("Record for {:?} is missing or changed.", record.component);
-                errors = true;
-            }
+// Substitute the environment variables referenced in the strings of a configuration value, but not
+// in its keys. The path names the key being substituted in error messages.
+fn substitute_env_value(value: &mut toml::Value, path: &str) -> Result<()> {
+    match value {
+        toml::Value::String(text) => {
+            *text = substitute_env(text).with_context(|| format!("Invalid value for {path}"))?;
         }
-        if !errors && !current.is_empty() {
-            for record in current {
-                println!("Extraneous record for {:?}.", record.component);
-                errors = true;
+        toml::Value::Array(values) => {
+            for value in values {
+                substitute_env_value(value, path)?;
             }
         }
-        if errors {
-            bail!("Current {DEST_FILENAME:?} is not up to date.")
-        } else {
-            Ok(())
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                substitute_env_value(value, &path)?;
+            }
         }
+        _ => (),
     }
+    Ok(())
 }
 
-fn build_everything(config: Option<PathBuf>) -> Result<Vec<Record>> {
-    let filename = config
-        .as_deref()
-        .unwrap_or_else(|| Path::new(CONFIG_FILENAME));
-    let config = Config::load(filename)?.unwrap_or_default();
-
-    let metadata = MetadataCommand::new()
-        .verbose(true)
-        .exec()
-        .context("Running `cargo metadata` failed")?;
-
-    let resolve = metadata
-        .resolve
-        .context("Metadata is missing a dependency tree")?;
-    let filtered = filter_deps(resolve);
-    let mut packages = lookup_deps(filtered, metadata.packages);
-    rewrite_packages(&mut packages, &config.overrides)?;
-    fixup_names(&mut packages)?;
-    lookup_all_copyrights(&mut packages)?;
-    Ok(build_records(packages))
+// Replace each `${NAME}` in the text with the environment variable, which must be set. With
+// `${NAME:-default}`, the default is used when the variable is not set or is empty, as in the shell.
+fn substitute_env(text: &str) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for captures in RE_ENV_VAR.captures_iter(text) {
+        let whole = captures.get(0).expect("The whole match is always captured");
+        result.push_str(&text[last..whole.start()]);
+        let name = &captures[1];
+        match (env::var(name), captures.get(2)) {
+            (Ok(value), None) => result.push_str(&value),
+            (Ok(value), Some(_)) if !value.is_empty() => result.push_str(&value),
+            (_, Some(default)) => result.push_str(default.as_str()),
+            (Err(_), None) => bail!("The environment variable {name} is not set"),
+        }
+        last = whole.end();
+    }
+    result.push_str(&text[last..]);
+    Ok(result)
 }
 
-// Given a list of package IDs, look up the corresponding entry from the package list and return an
-// array of the results.
-fn lookup_deps(package_ids: HashSet<PackageId>, packages: Vec<Package>) -> Vec<Package> {
-    let mut packages: HashMap<_, _> = packages
-        .into_iter()
-        .map(|package| (package.id.clone(), package))
-        .collect();
-    package_ids
-        .into_iter()
-        .map(|id| packages.remove(&id).expect("Missing package {id:?}"))
-        .filter(|package| package.source.is_some())
-        .collect()
+// A pattern of repository names for a component, as in `rust-{}` for the component in the `{}`.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+struct NamePattern {
+    prefix: Cow<'static, str>,
+    suffix: Cow<'static, str>,
 }
 
-// Filter the list of dependencies to exclude those that would not be distributed in a built
-// artifact. i.e. Skip those dependencies that are only used as build or dev dependencies.
-fn filter_deps(resolve: Resolve) -> HashSet<PackageId> {
-    let deps: HashMap<_, _> = resolve
-        .nodes
-        .into_iter()
-        .map(|node| (node.id.clone(), node))
-        .collect();
+impl NamePattern {
+    const fn new(prefix: &'static str, suffix: &'static str) -> Self {
+        Self {
+            prefix: Cow::Borrowed(prefix),
+            suffix: Cow::Borrowed(suffix),
+        }
+    }
 
-    let mut filtered = HashSet::new();
-    filter_deps_rec(resolve.root.as_ref(), &deps, &mut filtered);
-    filtered
+    // The component name matched by the pattern in a repository name, if any.
+    fn component<'a>(&self, repository: &'a str) -> Option<&'a str> {
+        repository
+            .strip_prefix(&*self.prefix)?
+            .strip_suffix(&*self.suffix)
+            .filter(|name| !name.is_empty())
+    }
 }
 
-fn filter_deps_rec(
-    node: Option<&PackageId>,
-    deps: &HashMap<PackageId, Node>,
-    packages: &mut HashSet<PackageId>,
-) {
-    match node {
-        Some(node) => filter_node_deps_rec(node, deps, packages),
-        None => {
-            // We're dealing with a workspace crate, so we iterate over all dependencies.
-            for pkg in deps.keys() {
-                filter_node_deps_rec(pkg, deps, packages);
-            }
+impl TryFrom<String> for NamePattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        match pattern.split_once("{}") {
+            Some((prefix, suffix)) if !suffix.contains("{}") => Ok(Self {
+                prefix: prefix.to_owned().into(),
+                suffix: suffix.to_owned().into(),
+            }),
+            _ => Err(format!(
+                "name reduction pattern {pattern:?} must contain `{{}}` exactly once"
+            )),
         }
     }
 }
 
-fn filter_node_deps_rec(
-    node: &PackageId,
-    deps: &HashMap<PackageId, Node>,
-    packages: &mut HashSet<PackageId>,
-) {
-    let root = deps.get(node).unwrap();
-    for node in &root.deps {
-        if is_normal_dep(&node.dep_kinds) {
-            packages.insert(node.pkg.clone());
-            filter_node_deps_rec(&node.pkg, deps, packages);
-        }
+// A regular expression in the configuration file, which is checked when it is loaded.
+#[derive(Deserialize)]
+#[serde(try_from = "String")]
+struct ConfigRegex(Regex);
+
+impl TryFrom<String> for ConfigRegex {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Regex::new(&pattern).map(Self)
     }
 }
 
-fn is_normal_dep(kinds: &[DepKindInfo]) -> bool {
-    kinds.iter().any(|dep| dep.kind == DependencyKind::Normal)
+// Custom names for the columns of the license file, keyed by the field name. Columns that are not
+// renamed keep their default header.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Headers {
+    component: Option<String>,
+    origin: Option<String>,
+    license: Option<String>,
+    copyright: Option<String>,
+    relation: Option<String>,
+    group: Option<String>,
+    dependents: Option<String>,
+    description: Option<String>,
+    download: Option<String>,
+    index_name: Option<String>,
+    notes: Option<String>,
 }
 
-// Translate the array of packages into an array of output records.
-fn build_records(packages: Vec<Package>) -> Vec<Record> {
-    let records = packages.into_iter().map(package_to_record);
-    let mut result: Vec<Record> = collect_record_sets(records)
+impl Headers {
+    // Pairs of the default and custom header of each renamed column.
+    fn renames(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.columns()
+            .into_iter()
+            .filter_map(|(name, header)| Some((name, header.as_deref()?)))
+    }
+
+    // Pairs of the default name and any custom header of every column.
+    fn columns(&self) -> [(&'static str, &Option<String>); 11] {
+        [
+            ("Component", &self.component),
+            ("Origin", &self.origin),
+            ("License", &self.license),
+            ("Copyright", &self.copyright),
+            ("Relation", &self.relation),
+            ("Group", &self.group),
+            ("Dependents", &self.dependents),
+            ("Description", &self.description),
+            ("Download", &self.download),
+            ("IndexName", &self.index_name),
+            ("Notes", &self.notes),
+        ]
+    }
+
+    // The header written for the column with the given default name.
+    fn header<'a>(&'a self, name: &'a str) -> &'a str {
+        self.renames()
+            .find_map(|(default, header)| (default == name).then_some(header))
+            .unwrap_or(name)
+    }
+
+    // The default name of the column with the given header, as read from the license file.
+    fn default_name<'a>(&'a self, header: &'a str) -> &'a str {
+        self.renames()
+            .find_map(|(default, custom)| (custom == header).then_some(default))
+            .unwrap_or(header)
+    }
+}
+
+fn main() -> Result<ExitCode> {
+    let mut args = Args::parse();
+    if let Commands::Completions { shell } = args.command {
+        write_completions(shell, io::stdout());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Commands::InstallHook { force, dry_run } = args.command {
+        install_hook(force, dry_run)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    args.resolve_directories()?;
+    if args.depth_limit().is_some() && matches!(args.command, Commands::Check { .. }) {
+        bail!("The `check` command cannot be used with a `--depth` limit.");
+    }
+    if args.config_check {
+        let filename = args.config_filename();
+        Config::load(filename)?.with_context(|| format!("Could not find {filename:?}"))?;
+        // Finding the overrides embedded in the workspace manifests needs `cargo metadata`, so
+        // they are only validated when asked for.
+        let manifest_paths = if args.check_manifests {
+            args.manifest_paths()
+        } else {
+            Vec::new()
+        };
+        for manifest_path in manifest_paths {
+            let mut command = metadata_command(&args, manifest_path);
+            let metadata = command.no_deps().exec().with_context(|| {
+                let command = display_command(&command.cargo_command());
+                format!("Running `{command}` failed")
+            })?;
+            embedded_overrides(&metadata.packages, &metadata.workspace_members)?;
+        }
+        println!("Configuration in {filename:?} is valid.");
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.emit_cargo_metadata_cmd {
+        for manifest_path in args.manifest_paths() {
+            println!(
+                "{}",
+                display_command(&metadata_command(&args, manifest_path).cargo_command())
+            );
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut config = Config::load(args.config_filename())?.unwrap_or_default();
+    args.no_copyright |= config.no_copyright;
+    args.strict |= config.strict;
+    if let Some(filename) = args.import_about.clone().or(config.import_about.take()) {
+        config.import_about(&filename)?;
+        // Kept for `--emit-make-deps`.
+        config.import_about = Some(filename);
+    }
+    let dest = args
+        .output
+        .clone()
+        .or_else(|| config.output.clone())
+        .unwrap_or_else(|| DEST_FILENAME.into());
+    let reused = if args.incremental {
+        ReusedCopyrights::load(&args, &dest, &config.headers)?
+    } else {
+        ReusedCopyrights::default()
+    };
+    let start = Instant::now();
+    let (records, stats) = match &args.command {
+        Commands::FromSbom { path } => (read_sbom(path, &args, &config)?, None),
+        Commands::Stats { existing: true, .. } => {
+            if !dest.is_file() {
+                bail!("Could not find {dest:?}");
+            }
+            (read_records(&dest, &config.headers)?, None)
+        }
+        _ => {
+            let (records, stats) = build_everything(&args, &config, &reused, &dest)?;
+            (records, Some(stats))
+        }
+    };
+    let count = records.len();
+    // Fail before writing anything on the warnings about the generated license data, and then on
+    // any from the command itself.
+    check_warnings(&args)?;
+    let code = args
+        .command
+        .doit(records, stats.as_ref(), &dest, &args, &config)?;
+    check_warnings(&args)?;
+    let summarize = match args.command {
+        Commands::Write { dry_run, .. } => !dry_run && !args.quiet,
+        Commands::Dump => args.verbose,
+        _ => false,
+    };
+    if let Some(stats) = stats.filter(|_| summarize) {
+        eprintln!(
+            "Recorded {count} components in {:.1}s: {} merged with other records, {} used author \
+             fallback, {} used overrides.",
+            start.elapsed().as_secs_f64(),
+            stats.merged,
+            stats.fallback,
+            stats.overridden,
+        );
+    }
+    Ok(code)
+}
+
+impl Commands {
+    fn doit(
+        &self,
+        records: Vec<Record>,
+        stats: Option<&Stats>,
+        dest: &Path,
+        args: &Args,
+        config: &Config,
+    ) -> Result<ExitCode> {
+        let headers = &config.headers;
+        let mut records = records;
+        if self.preserves_extra(config) {
+            let preserved = preserve_extra_records(&mut records, dest, headers, args)?;
+            if !preserved.is_empty() {
+                println!(
+                    "Preserved {} rows of {} for components that are not generated: {}",
+                    preserved.len(),
+                    dest.display(),
+                    preserved.join(", ")
+                );
+            }
+        }
+        match *self {
+            Self::Completions { .. } => unreachable!("Completions are printed before this"),
+            Self::InstallHook { .. } => unreachable!("The hook is installed before this"),
+            Self::Stats { format, .. } => print_stats(&records, stats, format, config),
+            Self::Dump if args.group_by_license => output_license_groups(records, headers),
+            Self::Dump => {
+                let annotations = Annotations::default();
+                output_table(
+                    records,
+                    headers,
+                    &annotations,
+                    args.include_hash,
+                    io::stdout(),
+                )
+            }
+            Self::Write { dry_run: true, .. } => return Self::dry_run(&records, dest, headers),
+            Self::Write {
+                force,
+                format: OutputFormat::Xlsx,
+                ..
+            } => {
+                if args.output.is_none() && config.output.is_none() {
+                    bail!("Writing with `--format xlsx` needs `--output` to name the workbook");
+                }
+                Self::write_xlsx(&records, dest, headers, force)
+            }
+            Self::Write {
+                force,
+                output_encoding,
+                ..
+            } => Self::write(records, dest, headers, args, force, output_encoding),
+            Self::FromSbom { .. } => {
+                Self::write(records, dest, headers, args, false, OutputEncoding::Utf8)
+            }
+            Self::Check {
+                diff_against: Some(ref revision),
+                ..
+            } => {
+                let baseline = read_records_at(revision, dest, headers)?;
+                Self::compare(
+                    &records,
+                    baseline,
+                    &format!("{} at {revision}", dest.display()),
+                )
+            }
+            Self::Check {
+                diff_base: Some(ref base),
+                ..
+            } => {
+                if !base.is_file() {
+                    bail!("Could not find {base:?}");
+                }
+                let baseline = read_records(base, headers)?;
+                Self::compare(&records, baseline, &base.display().to_string())
+            }
+            Self::Check {
+                update_on_fail,
+                tolerate,
+                ..
+            } => {
+                let result = Self::check(&records, dest, headers, args, tolerate);
+                if result.is_err() && update_on_fail {
+                    // Keep the byte order mark of the existing file, if it has one.
+                    let has_bom = fs::read(dest).is_ok_and(|data| data.starts_with(UTF8_BOM));
+                    let encoding = if has_bom {
+                        OutputEncoding::Utf8Bom
+                    } else {
+                        OutputEncoding::Utf8
+                    };
+                    Self::write(records, dest, headers, args, false, encoding)?;
+                    bail!("Updated {} — please review and commit.", dest.display());
+                }
+                result
+            }
+        }
+        .map(|()| ExitCode::SUCCESS)
+    }
+
+    // Whether the rows of the existing license file for components that are not generated are kept,
+    // which the configuration turns on for both `write` and `check` so they agree.
+    fn preserves_extra(&self, config: &Config) -> bool {
+        match *self {
+            Self::Write {
+                preserve_extra,
+                format: OutputFormat::Csv,
+                ..
+            } => preserve_extra || config.preserve_extra,
+            Self::Check {
+                diff_against: None,
+                diff_base: None,
+                ..
+            } => config.preserve_extra,
+            _ => false,
+        }
+    }
+
+    fn write(
+        records: Vec<Record>,
+        dest: &Path,
+        headers: &Headers,
+        args: &Args,
+        force: bool,
+        encoding: OutputEncoding,
+    ) -> Result<()> {
+        let annotations = if args.preserve_annotations {
+            Annotations::read(dest, headers)?
+        } else {
+            Annotations::default()
+        };
+        let provenance = match &args.metadata_file {
+            Some(filename) => Some((filename, Provenance::new(args, records.len())?)),
+            None => None,
+        };
+        let mut data = Vec::new();
+        if encoding == OutputEncoding::Utf8Bom {
+            data.extend_from_slice(UTF8_BOM);
+        }
+        output_table(records, headers, &annotations, args.include_hash, &mut data)?;
+        let mut changed = true;
+        if !force {
+            match fs::read(dest) {
+                Ok(current) if current == data => {
+                    println!("{} is up to date.", dest.display());
+                    changed = false;
+                }
+                Ok(current) if strip_crlf(&current) == data => {
+                    println!("Fixing line ending mismatch in {}.", dest.display());
+                }
+                Err(error) if error.kind() != ErrorKind::NotFound => {
+                    return Err(error).context(format!("Could not read {dest:?}"))
+                }
+                _ => (),
+            }
+        }
+        if changed {
+            write_file(dest, &data)?;
+        }
+        // The provenance is written even if the license file is unchanged, as the lockfile may
+        // still have changed.
+        if let Some((filename, provenance)) = provenance {
+            write_file(filename, &serde_json::to_vec_pretty(&provenance)?)?;
+        }
+        Ok(())
+    }
+
+    // Write the records to an Excel workbook, unless it would not change.
+    fn write_xlsx(records: &[Record], dest: &Path, headers: &Headers, force: bool) -> Result<()> {
+        let data = xlsx_workbook(records, headers)?;
+        if !force && fs::read(dest).is_ok_and(|current| current == data) {
+            println!("{} is up to date.", dest.display());
+            return Ok(());
+        }
+        write_file(dest, &data)
+    }
+
+    // Show a readable preview of the changes `write` would make, exiting with status 2 if there are
+    // any so scripts can branch on it.
+    fn dry_run(records: &[Record], dest: &Path, headers: &Headers) -> Result<ExitCode> {
+        let diff = RecordDiff::new(read_records(dest, headers)?, records);
+        if diff.is_empty() {
+            let current = fs::read(dest).unwrap_or_default();
+            if strip_crlf(&current) != current {
+                println!("{} has a line ending mismatch.", dest.display());
+                return Ok(ExitCode::from(2));
+            }
+            println!("{} is up to date.", dest.display());
+            return Ok(ExitCode::SUCCESS);
+        }
+        diff.print();
+        println!(
+            "{} would have {} added, {} changed, and {} removed records.",
+            dest.display(),
+            diff.added.len(),
+            diff.changed.len(),
+            diff.removed.len()
+        );
+        Ok(ExitCode::from(2))
+    }
+
+    // Show the changes in the license data from a baseline, failing if there are any.
+    fn compare(records: &[Record], baseline: Vec<Record>, name: &str) -> Result<()> {
+        let diff = RecordDiff::new(baseline, records);
+        if diff.is_empty() {
+            println!("The license data has not changed from {name}.");
+            return Ok(());
+        }
+        diff.print();
+        bail!(
+            "The license data has {} added, {} changed, and {} removed records from {name}.",
+            diff.added.len(),
+            diff.changed.len(),
+            diff.removed.len()
+        )
+    }
+
+    fn check(
+        records: &[Record],
+        dest: &Path,
+        headers: &Headers,
+        args: &Args,
+        tolerate: Option<Tolerate>,
+    ) -> Result<()> {
+        if !args.preserve_annotations && !Annotations::read(dest, headers)?.headers.is_empty() {
+            bail!(
+                "Current {dest:?} has annotation columns. Use `--preserve-annotations` to ignore \
+                 them."
+            );
+        }
+        let mut current = read_records(dest, headers)?;
+        // The hash covers the records exactly as they are in the file, before any columns that
+        // are not compared are dropped.
+        let current_hash = records_hash(&current);
+        if !args.include_notes {
+            // The notes are only compared when they are included.
+            for record in &mut current {
+                record.notes = None;
+            }
+        }
+        let has_copyright = current.iter().any(|record| record.copyright.is_some());
+        if !current.is_empty() && has_copyright == args.no_copyright {
+            if has_copyright {
+                bail!(
+                    "Current {dest:?} has a Copyright column, but copyrights are disabled by \
+                     `--no-copyright`. Run `write` to remove the column."
+                );
+            }
+            bail!(
+                "Current {dest:?} has no Copyright column. Use `--no-copyright` to check it, or \
+                 run `write` to add the column."
+            );
+        }
+        let diff = RecordDiff::new(current, records);
+        let mut added = diff.added.iter().collect::<Vec<_>>();
+        let mut removed = diff.removed.iter().collect::<Vec<_>>();
+        if tolerate == Some(Tolerate::Additions) {
+            for record in take(&mut added) {
+                warn!(
+                    Warning::ToleratedChange,
+                    "Record for {:?} is missing. Run `write` to add it.", record.component
+                );
+            }
+        }
+        if tolerate == Some(Tolerate::Removals) {
+            for record in take(&mut removed) {
+                warn!(
+                    Warning::ToleratedChange,
+                    "Extraneous record for {:?}. Run `write` to remove it.", record.component
+                );
+            }
+        }
+        let mut missing: Vec<_> = added
+            .into_iter()
+            .chain(diff.changed.iter().map(|(_, record)| record))
+            .collect();
+        missing.sort();
+        for record in &missing {
+            println!("Record for {:?} is missing or changed.", record.component);
+        }
+        if missing.is_empty() {
+            for record in &removed {
+                println!("Extraneous record for {:?}.", record.component);
+            }
+        }
+        if !missing.is_empty() || !removed.is_empty() {
+            bail!("Current {dest:?} is not up to date.")
+        }
+        if args.include_hash {
+            match read_hash(dest)? {
+                None => bail!(
+                    "Current {dest:?} has no hash line. Run `write` with `--include-hash` to add \
+                     it."
+                ),
+                Some(hash) if hash != current_hash => bail!(
+                    "The hash in current {dest:?} does not match its records, so the file may have \
+                     been modified."
+                ),
+                Some(_) => (),
+            }
+        }
+        if let Some(filename) = &args.metadata_file {
+            Provenance::check(filename, args)?;
+        }
+        Ok(())
+    }
+}
+
+// Write a file through a temporary file that is renamed into place, so it is never left partially
+// written. The permissions of any existing file are kept, rather than those of a new file.
+fn write_file(dest: &Path, data: &[u8]) -> Result<()> {
+    let mut temp_filename = dest.as_os_str().to_owned();
+    temp_filename.push(format!(".tmp.{}", std::process::id()));
+    let mut out = File::create(&temp_filename)
+        .with_context(|| format!("Could not create {temp_filename:?}"))?;
+    out.write_all(data)
+        .with_context(|| format!("Could not write {temp_filename:?}"))?;
+    if let Ok(metadata) = fs::metadata(dest) {
+        out.set_permissions(metadata.permissions())
+            .with_context(|| format!("Could not set the permissions of {temp_filename:?}"))?;
+    }
+    fs::rename(&temp_filename, dest)
+        .with_context(|| format!("Could not rename {temp_filename:?} to {dest:?}"))
+}
+
+// Convert CRLF line endings to LF, to detect files that differ only in their line endings.
+fn strip_crlf(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    for (index, &byte) in data.iter().enumerate() {
+        if byte != b'\r' || data.get(index + 1) != Some(&b'\n') {
+            result.push(byte);
+        }
+    }
+    result
+}
+
+// ANSI color codes used for readable output on terminals.
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+
+// Read the records from an existing license file, treating a missing file as empty. Custom headers
+// are mapped back to the default column names.
+fn read_records(filename: &Path, headers: &Headers) -> Result<Vec<Record>> {
+    let Some(reader) = open_license_file(filename)? else {
+        return Ok(Vec::new());
+    };
+    parse_records(reader, headers, &filename.display().to_string())
+        .with_context(|| format!("Could not read current {filename:?}"))
+}
+
+// Write the shell completion script for this tool.
+fn write_completions(shell: Shell, mut writer: impl Write) {
+    let mut command = Args::command();
+    let name = command.get_name().to_owned();
+    clap_complete::generate(shell, &mut command, name, &mut writer);
+}
+
+// Install a git pre-commit hook running `check` with the options given before the `install-hook`
+// subcommand, from the current directory.
+fn install_hook(force: bool, dry_run: bool) -> Result<()> {
+    let git = |args: &[&str]| -> Result<String> {
+        let output = Command::new("git")
+            .args(args)
+            .output()
+            .context("Could not run `git`")?;
+        if !output.status.success() {
+            bail!(
+                "Running `git {}` failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let stdout = String::from_utf8(output.stdout).context("Invalid output from `git`")?;
+        Ok(stdout.trim_end_matches('\n').to_owned())
+    };
+    let name = Args::command().get_name().to_owned();
+    let mut command = Command::new(&name);
+    command.args(
+        env::args_os()
+            .skip(1)
+            .take_while(|arg| arg != "install-hook"),
+    );
+    command.arg("check");
+    // Hooks run from the top of the working tree, so change back to the current directory for any
+    // relative paths in the options.
+    let prefix = git(&["rev-parse", "--show-prefix"])?;
+    let mut hook = format!("#!/bin/sh\n# Installed by `{name} install-hook`.\n");
+    hook += "# Blocks the commit if the license data is out of date.\n";
+    if !prefix.is_empty() {
+        hook += &format!("cd {} || exit 1\n", display_command(&Command::new(&prefix)));
+    }
+    hook += &format!("exec {}\n", display_command(&command));
+    if dry_run {
+        print!("{hook}");
+        return Ok(());
+    }
+    // This honours `core.hooksPath` and linked worktrees.
+    let hooks = PathBuf::from(git(&[
+        "rev-parse",
+        "--path-format=absolute",
+        "--git-path",
+        "hooks",
+    ])?);
+    let path = hooks.join("pre-commit");
+    match fs::read(&path) {
+        Ok(existing) if existing == hook.as_bytes() => {
+            println!("The pre-commit hook is already installed in {path:?}.");
+            return Ok(());
+        }
+        Ok(_) if !force => {
+            bail!("A different pre-commit hook exists in {path:?}, use `--force` to replace it.")
+        }
+        Ok(_) => {}
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => return Err(error).with_context(|| format!("Could not read {path:?}")),
+    }
+    fs::create_dir_all(&hooks).with_context(|| format!("Could not create {hooks:?}"))?;
+    write_file(&path, hook.as_bytes())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Could not make {path:?} executable"))?;
+    }
+    println!("Installed the pre-commit hook in {path:?}.");
+    Ok(())
+}
+
+// Read the records from the license file as it was at a git revision, treating a file that did not
+// exist at that revision as empty.
+fn read_records_at(revision: &str, filename: &Path, headers: &Headers) -> Result<Vec<Record>> {
+    // Relative paths are looked up from the current directory, which need not exist at the
+    // revision, and absolute paths from the directory containing the file.
+    let (directory, path) = match filename.parent() {
+        Some(parent) if filename.is_absolute() => (parent, filename.file_name().map(OsStr::new)),
+        _ => (Path::new("."), Some(filename.as_os_str())),
+    };
+    let path = path
+        .and_then(OsStr::to_str)
+        .with_context(|| format!("{filename:?} is not a valid file name"))?;
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(directory)
+            .args(args)
+            .output()
+            .context("Could not run `git`")
+    };
+    if !git(&["rev-parse", "--git-dir"])?.status.success() {
+        bail!("{directory:?} is not in a git repository");
+    }
+    let commit = format!("{revision}^{{commit}}");
+    if !git(&["rev-parse", "--verify", "--quiet", &commit])?
+        .status
+        .success()
+    {
+        bail!("Unknown git revision {revision:?}");
+    }
+    // The `./` makes the path relative to the directory rather than the root of the repository.
+    let spec = format!("{revision}:./{path}");
+    if !git(&["cat-file", "-e", &spec])?.status.success() {
+        println!("{filename:?} did not exist at {revision}, treating it as empty.");
+        return Ok(Vec::new());
+    }
+    let output = git(&["show", &spec])?;
+    if !output.status.success() {
+        bail!(
+            "Could not read {filename:?} at {revision}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let source = format!("{} at {revision}", filename.display());
+    parse_records(license_reader(&output.stdout[..]), headers, &source)
+        .with_context(|| format!("Could not read {filename:?} at {revision}"))
+}
+
+// Parse the records of a license file, mapping custom headers back to the default column names so
+// that the columns may be in any order. Unknown columns, such as ones added by hand in a
+// spreadsheet, are ignored with a warning, apart from the `#` annotation columns.
+fn parse_records<R: io::Read>(
+    mut reader: csv::Reader<R>,
+    headers: &Headers,
+    source: &str,
+) -> Result<Vec<Record>> {
+    let names: csv::StringRecord = reader
+        .headers()?
+        .iter()
+        .map(|header| headers.default_name(header))
+        .collect();
+    // An empty file has no header at all, and so no records to check the columns of.
+    if !names.is_empty() {
+        for required in REQUIRED_COLUMNS {
+            if !names.iter().any(|name| name == required) {
+                bail!("Missing the {:?} column", headers.header(required));
+            }
+        }
+    }
+    let columns = headers.columns();
+    for name in &names {
+        if !name.starts_with('#') && !columns.iter().any(|(column, _)| *column == name) {
+            warn!(
+                Warning::UnknownColumn,
+                "Ignoring the unknown column {name:?} in {source}"
+            );
+        }
+    }
+    reader.set_headers(names);
+    Ok(reader.into_deserialize().collect::<csv::Result<_>>()?)
+}
+
+// Open an existing license file for reading, returning `None` if it does not exist.
+fn open_license_file(filename: &Path) -> Result<Option<csv::Reader<File>>> {
+    match File::open(filename) {
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error).context(format!("Could not read {filename:?}")),
+        Ok(file) => Ok(Some(license_reader(file))),
+    }
+}
+
+// Set up a CSV reader for license data. Files checked out on Windows may have CRLF line endings,
+// which are treated the same as LF so that the records compare equal. Lines starting with `#`, such
+// as the hash line, are skipped.
+fn license_reader<R: io::Read>(reader: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .terminator(csv::Terminator::CRLF)
+        .comment(Some(b'#'))
+        .from_reader(reader)
+}
+
+// The parts of an SPDX 2.3 JSON document that are imported by `from-sbom`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SpdxDocument {
+    #[serde(default)]
+    document_describes: Vec<String>,
+    #[serde(default)]
+    packages: Vec<SpdxPackage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SpdxPackage {
+    #[serde(rename = "SPDXID")]
+    spdx_id: String,
+    name: String,
+    download_location: Option<String>,
+    license_concluded: Option<String>,
+    copyright_text: Option<String>,
+}
+
+// The parts of a CycloneDX 1.4 JSON document that are imported by `from-sbom`.
+#[derive(Deserialize)]
+struct CycloneDxBom {
+    #[serde(default)]
+    components: Vec<CycloneDxComponent>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CycloneDxComponent {
+    name: String,
+    #[serde(default)]
+    licenses: Vec<CycloneDxLicenseChoice>,
+    copyright: Option<String>,
+    #[serde(default)]
+    external_references: Vec<CycloneDxReference>,
+}
+
+// A license entry, which is either a single license or an SPDX expression.
+#[derive(Deserialize)]
+struct CycloneDxLicenseChoice {
+    license: Option<CycloneDxLicense>,
+    expression: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CycloneDxLicense {
+    id: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CycloneDxReference {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+}
+
+// Read the records from the packages of an SPDX or CycloneDX JSON SBOM. The packages the SPDX
+// document describes are the project itself, so they are left out.
+fn read_sbom(filename: &Path, args: &Args, config: &Config) -> Result<Vec<Record>> {
+    let data = fs::read(filename).with_context(|| format!("Could not read {filename:?}"))?;
+    let document: Value = serde_json::from_slice(&data)
+        .with_context(|| format!("Could not parse {filename:?} as JSON"))?;
+    let records = if document.get("spdxVersion").is_some() {
+        let document = SpdxDocument::deserialize(&document)
+            .with_context(|| format!("Invalid SPDX document {filename:?}"))?;
+        document
+            .packages
+            .into_iter()
+            .filter(|package| !document.document_describes.contains(&package.spdx_id))
+            .map(|package| {
+                sbom_record(
+                    package.name,
+                    &spdx_value(package.download_location),
+                    spdx_value(package.license_concluded),
+                    spdx_value(package.copyright_text),
+                    args,
+                )
+            })
+            .collect()
+    } else if document.get("bomFormat").and_then(Value::as_str) == Some("CycloneDX") {
+        let bom = CycloneDxBom::deserialize(&document)
+            .with_context(|| format!("Invalid CycloneDX document {filename:?}"))?;
+        bom.components
+            .into_iter()
+            .map(|component| {
+                // Prefer the source repository over the other kinds of references.
+                let origin = ["vcs", "website", "distribution"]
+                    .iter()
+                    .find_map(|kind| {
+                        let mut references = component.external_references.iter();
+                        references.find(|reference| reference.kind == *kind)
+                    })
+                    .map(|reference| reference.url.clone())
+                    .unwrap_or_default();
+                let licenses: Vec<String> = component
+                    .licenses
+                    .into_iter()
+                    .filter_map(|choice| {
+                        choice.expression.or_else(|| {
+                            choice
+                                .license
+                                .and_then(|license| license.id.or(license.name))
+                        })
+                    })
+                    .collect();
+                let copyright = component.copyright.unwrap_or_default();
+                sbom_record(
+                    component.name,
+                    &origin,
+                    licenses.join(" AND "),
+                    copyright,
+                    args,
+                )
+            })
+            .collect()
+    } else {
+        bail!("{filename:?} is not an SPDX or CycloneDX JSON document");
+    };
+
+    Ok(merge_records(records, args, config))
+}
+
+// Build a record from the details of a package imported from an SBOM.
+fn sbom_record(
+    component: String,
+    origin: &str,
+    license: String,
+    copyright: String,
+    args: &Args,
+) -> Record {
+    let origin = origin.strip_prefix("git+").unwrap_or(origin);
+    let origin = normalize_origin(origin).unwrap_or_else(|_| {
+        if !origin.is_empty() {
+            warn!(
+                Warning::InvalidOrigin,
+                "Package {component} has an invalid origin URL {origin:?}"
+            );
+        }
+        origin.into()
+    });
+    Record {
+        component,
+        origin,
+        license,
+        copyright: (!args.no_copyright).then_some(copyright),
+        relation: None,
+        group: None,
+        dependents: None,
+        description: None,
+        download: None,
+        index_name: None,
+        notes: None,
+    }
+}
+
+// Convert an SPDX field value to the text output, where the placeholders for missing values are
+// output as empty.
+fn spdx_value(value: Option<String>) -> String {
+    value
+        .filter(|value| value != "NOASSERTION" && value != "NONE")
+        .unwrap_or_default()
+}
+
+// The annotation columns of an existing license file, which are the columns with a header starting
+// with `#`, along with their values for each component.
+#[derive(Default)]
+struct Annotations {
+    headers: Vec<String>,
+    values: HashMap<String, Vec<String>>,
+}
+
+impl Annotations {
+    fn read(filename: &Path, headers: &Headers) -> Result<Self> {
+        let Some(mut reader) = open_license_file(filename)? else {
+            return Ok(Self::default());
+        };
+        let columns = reader
+            .headers()
+            .with_context(|| format!("Could not read current {filename:?}"))?
+            .clone();
+        let indexes: Vec<usize> = (0..columns.len())
+            .filter(|&index| columns[index].starts_with('#'))
+            .collect();
+        let component = columns
+            .iter()
+            .position(|header| header == headers.header("Component"));
+        let mut values = HashMap::new();
+        if let (Some(component), false) = (component, indexes.is_empty()) {
+            for row in reader.records() {
+                let row = row.with_context(|| format!("Could not read current {filename:?}"))?;
+                let notes = indexes.iter().map(|&index| row[index].to_owned()).collect();
+                values.insert(row[component].to_owned(), notes);
+            }
+        }
+        let headers = indexes
+            .iter()
+            .map(|&index| columns[index].to_owned())
+            .collect();
+        Ok(Self { headers, values })
+    }
+
+    // The annotations of a component, which are empty for components not in the existing file.
+    fn get(&self, component: &str) -> Vec<String> {
+        self.values
+            .get(component)
+            .cloned()
+            .unwrap_or_else(|| vec![String::new(); self.headers.len()])
+    }
+}
+
+// The differences between the current license data and the generated records. Records that differ
+// but have the same component name are paired up as changes.
+#[derive(Default)]
+struct RecordDiff {
+    added: Vec<Record>,
+    // Pairs of the current and generated records.
+    changed: Vec<(Record, Record)>,
+    removed: Vec<Record>,
+}
+
+impl RecordDiff {
+    fn new(current: Vec<Record>, records: &[Record]) -> Self {
+        let mut current: HashSet<Record> = current.into_iter().collect();
+        let unmatched: Vec<&Record> = records
+            .iter()
+            .filter(|record| !current.remove(*record))
+            .collect();
+        let mut leftover: HashMap<String, Vec<Record>> = HashMap::new();
+        for record in current {
+            leftover
+                .entry(record.component.clone())
+                .or_default()
+                .push(record);
+        }
+        let mut diff = Self::default();
+        for record in unmatched {
+            match leftover.get_mut(&record.component).and_then(Vec::pop) {
+                Some(current) => diff.changed.push((current, record.clone())),
+                None => diff.added.push(record.clone()),
+            }
+        }
+        diff.removed = leftover.into_values().flatten().collect();
+        diff.removed.sort();
+        diff
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    // Print a readable listing of the differences, colored when writing to a terminal.
+    fn print(&self) {
+        let color = io::stdout().is_terminal();
+        let paint = |code: &str, text: &str| {
+            if color {
+                format!("\x1b[{code}m{text}\x1b[0m")
+            } else {
+                text.into()
+            }
+        };
+        if !self.added.is_empty() {
+            println!("Added records:");
+            for record in &self.added {
+                let line = format!("  + {} ({})", record.component, record.license);
+                println!("{}", paint(GREEN, &line));
+            }
+        }
+        if !self.changed.is_empty() {
+            println!("Changed records:");
+            for (current, record) in &self.changed {
+                println!("{}", paint(YELLOW, &format!("  ~ {}", record.component)));
+                for (name, from, to) in current.changed_fields(record) {
+                    println!(
+                        "      {name}: {} -> {}",
+                        paint(RED, &from),
+                        paint(GREEN, &to)
+                    );
+                }
+            }
+        }
+        if !self.removed.is_empty() {
+            println!("Removed records:");
+            for record in &self.removed {
+                let line = format!("  - {} ({})", record.component, record.license);
+                println!("{}", paint(RED, &line));
+            }
+        }
+    }
+}
+
+fn build_everything(
+    args: &Args,
+    config: &Config,
+    reused: &ReusedCopyrights,
+    dest: &Path,
+) -> Result<(Vec<Record>, Stats)> {
+    let start = Instant::now();
+    let (packages, mut stats, graph) = build_all_packages(args, config, reused)?;
+    if let Some(filename) = &args.emit_dot {
+        write_file(filename, graph.to_dot().as_bytes())?;
+    }
+    if let Some(filename) = &args.emit_make_deps {
+        let mut inputs = stats.inputs.clone();
+        let config_filename = args.config_filename();
+        if config_filename.is_file() {
+            inputs.insert(config_filename.into());
+        }
+        inputs.extend(config.import_about.clone());
+        write_file(filename, make_deps(dest, &inputs).as_bytes())?;
+    }
+    if let Some(filename) = &args.override_log {
+        write_override_log(&packages, filename)?;
+    }
+    if args.require_all_overrides_matched {
+        check_overrides_matched(&packages, &config.overrides)?;
+    }
+    // Each package makes one record before they are merged.
+    let unmerged = packages.len();
+    let records = build_records(packages, args, config)?;
+    stats.merged = unmerged - records.len();
+    check_collisions(&records);
+    check_duplicate_origins(&records, args.error_on_duplicate_origin)?;
+    if args.validate_urls {
+        validate_urls(&records, args);
+    }
+    if args.stats || args.verbose {
+        eprintln!(
+            "Scanned {} packages in {:.1}s: {} had detected copyrights, {} used author fallback, \
+             {} used overrides.",
+            stats.packages,
+            start.elapsed().as_secs_f64(),
+            stats.detected,
+            stats.fallback,
+            stats.overridden,
+        );
+        if args.incremental {
+            eprintln!(
+                "Reused the copyrights of {} unchanged packages.",
+                stats.reused
+            );
+        }
+    }
+    if args.stats || args.verbose || args.report.contains(&Report::And) {
+        report_combined_licenses(&records);
+    }
+    if args.report.contains(&Report::Categories) {
+        report_license_categories(&records, config);
+    }
+    Ok((records, stats))
+}
+
+// List the components whose licenses all apply, as their obligations need the closest review.
+fn report_combined_licenses(records: &[Record]) {
+    let combined: Vec<&Record> = records
+        .iter()
+        .filter(|record| is_combined_license(&record.license))
+        .collect();
+    if combined.is_empty() {
+        eprintln!("No components require satisfying multiple licenses.");
+        return;
+    }
+    eprintln!(
+        "{} components require satisfying multiple licenses:",
+        combined.len()
+    );
+    for record in combined {
+        eprintln!("    {}: {}", record.component, record.license);
+    }
+}
+
+// Count the components in each license category, listing the components that are not permissive and
+// the license identifiers that are not recognized, which usually point to bad metadata.
+fn report_license_categories(records: &[Record], config: &Config) {
+    let (buckets, unknown) = license_categories(records, config);
+    eprintln!("License categories:");
+    for category in LicenseCategory::ALL {
+        let count = buckets.get(&category).map_or(0, Vec::len);
+        eprintln!("    {}: {count}", category.as_str());
+    }
+    for (category, records) in &buckets {
+        if *category != LicenseCategory::Permissive {
+            eprintln!("Components with {} licenses:", category.as_str());
+            for record in records {
+                eprintln!("    {}: {}", record.component, record.license);
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        let unknown: Vec<&str> = unknown.iter().map(String::as_str).collect();
+        eprintln!("Unrecognized license identifiers: {}", unknown.join(", "));
+    }
+}
+
+// Sort the records into the categories of their licenses, along with the license identifiers that
+// have no category.
+fn license_categories<'a>(
+    records: &'a [Record],
+    config: &Config,
+) -> (BTreeMap<LicenseCategory, Vec<&'a Record>>, BTreeSet<String>) {
+    let mut classifier = LicenseClassifier {
+        categories: &config.license_categories,
+        unknown: BTreeSet::new(),
+    };
+    let mut buckets: BTreeMap<LicenseCategory, Vec<&Record>> = BTreeMap::new();
+    for record in records {
+        // Licenses are classified by their identifiers rather than their display names.
+        let license = unalias_license(&record.license, &config.aliases);
+        let category = classifier.classify(&license);
+        buckets.entry(category).or_default().push(record);
+    }
+    (buckets, classifier.unknown)
+}
+
+// Replace the display names of the license aliases in an expression with their identifiers. Only
+// whole names are replaced, so an alias is not found within a longer identifier, like `MIT` within
+// `MIT-0`, while a display name may still contain spaces.
+fn unalias_license(license: &str, aliases: &BTreeMap<String, String>) -> String {
+    let is_boundary = |c: char| c.is_whitespace() || c == '(' || c == ')';
+    // Longer names are tried first, so a name is not cut short by another that starts it.
+    let mut names: Vec<(&str, &str)> = aliases
+        .iter()
+        .filter(|(_, alias)| !alias.is_empty())
+        .map(|(id, alias)| (alias.as_str(), id.as_str()))
+        .collect();
+    names.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+    let mut unaliased = String::with_capacity(license.len());
+    let mut rest = license;
+    let mut at_boundary = true;
+    while let Some(c) = rest.chars().next() {
+        if at_boundary {
+            let found = names.iter().find(|(alias, _)| {
+                rest.starts_with(alias) && !rest[alias.len()..].starts_with(|c| !is_boundary(c))
+            });
+            if let Some((alias, id)) = found {
+                unaliased.push_str(id);
+                rest = &rest[alias.len()..];
+                at_boundary = false;
+                continue;
+            }
+        }
+        unaliased.push(c);
+        rest = &rest[c.len_utf8()..];
+        at_boundary = is_boundary(c);
+    }
+    unaliased
+}
+
+// Classifies license expressions, where `OR` offers the most permissive of its choices and `AND`
+// requires the most restrictive of its terms.
+struct LicenseClassifier<'a> {
+    categories: &'a LicenseCategories,
+    // The identifiers seen that have no category.
+    unknown: BTreeSet<String>,
+}
+
+impl LicenseClassifier<'_> {
+    fn classify(&mut self, license: &str) -> LicenseCategory {
+        let spaced = license.replace('(', " ( ").replace(')', " ) ");
+        let mut tokens = spaced.split_whitespace().peekable();
+        self.classify_or(&mut tokens)
+    }
+
+    fn classify_or<'t>(
+        &mut self,
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+    ) -> LicenseCategory {
+        let mut category = self.classify_and(tokens);
+        while tokens.next_if_eq(&"OR").is_some() {
+            category = category.min(self.classify_and(tokens));
+        }
+        category
+    }
+
+    fn classify_and<'t>(
+        &mut self,
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+    ) -> LicenseCategory {
+        let mut category = self.classify_term(tokens);
+        while tokens.next_if_eq(&"AND").is_some() {
+            category = category.max(self.classify_term(tokens));
+        }
+        category
+    }
+
+    // Classify a parenthesized expression or a single license, ignoring any exception added to it.
+    fn classify_term<'t>(
+        &mut self,
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+    ) -> LicenseCategory {
+        match tokens.next() {
+            Some("(") => {
+                let category = self.classify_or(tokens);
+                tokens.next_if_eq(&")");
+                category
+            }
+            Some(id) => {
+                if tokens.next_if_eq(&"WITH").is_some() {
+                    tokens.next();
+                }
+                let category = self
+                    .categories
+                    .get(id)
+                    .copied()
+                    .or_else(|| LicenseCategory::builtin(id));
+                category.unwrap_or_else(|| {
+                    self.unknown.insert(id.into());
+                    LicenseCategory::Unknown
+                })
+            }
+            None => LicenseCategory::Unknown,
+        }
+    }
+}
+
+// Dependency licenses that cannot be included in a project under the given license, besides the
+// strong copyleft licenses that only a project under a strong copyleft license can include. The
+// identifiers are in the form given by `normalize_spdx_id`.
+const SPDX_INCOMPATIBLE: [(&str, &[&str]); 6] = [
+    (
+        "GPL-2.0-only",
+        &[
+            "AGPL-3.0-only",
+            "AGPL-3.0-or-later",
+            "Apache-2.0",
+            "CDDL-1.0",
+            "CDDL-1.1",
+            "EPL-1.0",
+            "EPL-2.0",
+            "EUPL-1.2",
+            "GPL-3.0-only",
+            "GPL-3.0-or-later",
+            "LGPL-3.0-only",
+            "LGPL-3.0-or-later",
+            "MPL-1.1",
+        ],
+    ),
+    (
+        "GPL-2.0-or-later",
+        &["CDDL-1.0", "CDDL-1.1", "EPL-1.0", "EPL-2.0", "MPL-1.1"],
+    ),
+    (
+        "GPL-3.0-only",
+        &[
+            "CDDL-1.0",
+            "CDDL-1.1",
+            "EPL-1.0",
+            "EPL-2.0",
+            "GPL-2.0-only",
+            "MPL-1.1",
+        ],
+    ),
+    (
+        "GPL-3.0-or-later",
+        &[
+            "CDDL-1.0",
+            "CDDL-1.1",
+            "EPL-1.0",
+            "EPL-2.0",
+            "GPL-2.0-only",
+            "MPL-1.1",
+        ],
+    ),
+    (
+        "AGPL-3.0-only",
+        &[
+            "CDDL-1.0",
+            "CDDL-1.1",
+            "EPL-1.0",
+            "EPL-2.0",
+            "GPL-2.0-only",
+            "MPL-1.1",
+        ],
+    ),
+    (
+        "AGPL-3.0-or-later",
+        &[
+            "CDDL-1.0",
+            "CDDL-1.1",
+            "EPL-1.0",
+            "EPL-2.0",
+            "GPL-2.0-only",
+            "MPL-1.1",
+        ],
+    ),
+];
+
+// Convert the deprecated forms of the GNU license identifiers, like `GPL-2.0` and `GPL-2.0+`, to
+// their `-only` and `-or-later` forms.
+fn normalize_spdx_id(id: &str) -> Cow<'_, str> {
+    let gnu = ["GPL-", "LGPL-", "AGPL-"]
+        .iter()
+        .any(|prefix| id.starts_with(prefix));
+    if !gnu || id.ends_with("-only") || id.ends_with("-or-later") {
+        return Cow::Borrowed(id);
+    }
+    match id.strip_suffix('+') {
+        Some(id) => Cow::Owned(format!("{id}-or-later")),
+        None => Cow::Owned(format!("{id}-only")),
+    }
+}
+
+// Warn about the dependencies whose licenses cannot be included in a project under the given
+// license. A dependency is compatible when any choice offered by `OR` is compatible with every
+// license of the project, and licenses of unknown or other categories are not judged.
+fn check_spdx_compatibility<'a>(
+    project: &str,
+    dependencies: impl Iterator<Item = &'a Package>,
+    config: &Config,
+) {
+    let mut classifier = LicenseClassifier {
+        categories: &config.license_categories,
+        unknown: BTreeSet::new(),
+    };
+    let project_ids: Vec<(Cow<str>, LicenseCategory)> = license_ids(project)
+        .into_iter()
+        .map(|id| (normalize_spdx_id(id), classifier.classify(id)))
+        .collect();
+    let mut compatible = |id: &str| {
+        let category = classifier.classify(id);
+        let id = normalize_spdx_id(id);
+        project_ids.iter().all(|(project_id, project_category)| {
+            let incompatible = SPDX_INCOMPATIBLE
+                .iter()
+                .find(|(license, _)| license == project_id)
+                .is_some_and(|(_, licenses)| licenses.contains(&&*id));
+            match category {
+                _ if incompatible => false,
+                LicenseCategory::StrongCopyleft => {
+                    *project_category == LicenseCategory::StrongCopyleft
+                }
+                _ => true,
+            }
+        })
+    };
+    for package in dependencies {
+        let Some(license) = &package.license else {
+            continue;
+        };
+        if !license_satisfies(license, &mut compatible) {
+            warn!(
+                Warning::IncompatibleLicense,
+                "License {license:?} of {} {} is not compatible with the project license \
+                 {project:?}.",
+                package.name,
+                package.version
+            );
+        }
+    }
+}
+
+// The license identifiers in a license expression, leaving out the operators and exceptions.
+fn license_ids(license: &str) -> Vec<&str> {
+    let mut ids = Vec::new();
+    let mut tokens = license.split(|c: char| c.is_whitespace() || c == '(' || c == ')');
+    while let Some(token) = tokens.next() {
+        match token {
+            "" | "AND" | "OR" => (),
+            "WITH" => {
+                tokens.find(|token| !token.is_empty());
+            }
+            id => ids.push(id),
+        }
+    }
+    ids
+}
+
+// Check whether a license expression satisfies a test of single licenses, which any choice offered
+// by `OR` may pass and every license combined with `AND` must pass. Exceptions are ignored.
+fn license_satisfies(license: &str, test: &mut impl FnMut(&str) -> bool) -> bool {
+    fn satisfies_or<'t>(
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+        test: &mut impl FnMut(&str) -> bool,
+    ) -> bool {
+        let mut result = satisfies_and(tokens, test);
+        while tokens.next_if_eq(&"OR").is_some() {
+            // Every choice is parsed, even once one passes.
+            result |= satisfies_and(tokens, test);
+        }
+        result
+    }
+
+    fn satisfies_and<'t>(
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+        test: &mut impl FnMut(&str) -> bool,
+    ) -> bool {
+        let mut result = satisfies_term(tokens, test);
+        while tokens.next_if_eq(&"AND").is_some() {
+            result &= satisfies_term(tokens, test);
+        }
+        result
+    }
+
+    fn satisfies_term<'t>(
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+        test: &mut impl FnMut(&str) -> bool,
+    ) -> bool {
+        match tokens.next() {
+            Some("(") => {
+                let result = satisfies_or(tokens, test);
+                tokens.next_if_eq(&")");
+                result
+            }
+            Some(id) => {
+                if tokens.next_if_eq(&"WITH").is_some() {
+                    tokens.next();
+                }
+                test(id)
+            }
+            None => true,
+        }
+    }
+
+    let spaced = license.replace('(', " ( ").replace(')', " ) ");
+    satisfies_or(&mut spaced.split_whitespace().peekable(), test)
+}
+
+// Check if a license expression has an `AND` or `WITH` operator outside of any parentheses, so
+// that more than one set of terms applies whichever choice is made.
+fn is_combined_license(license: &str) -> bool {
+    let mut depth = 0usize;
+    license
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .any(|token| {
+            match token {
+                "(" => depth += 1,
+                ")" => depth = depth.saturating_sub(1),
+                _ => return depth == 0 && matches!(token, "AND" | "WITH"),
+            }
+            false
+        })
+}
+
+// Fail if any warnings were printed since the last check with `--strict`, apart from the allowed
+// kinds, listing the number of each kind.
+fn check_warnings(args: &Args) -> Result<()> {
+    let warnings = take(&mut *WARNINGS.lock().unwrap_or_else(PoisonError::into_inner));
+    if !args.strict {
+        return Ok(());
+    }
+    let failed: Vec<(Warning, usize)> = warnings
+        .into_iter()
+        .filter(|(kind, _)| !args.allow.contains(kind))
+        .collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    eprintln!("Warnings treated as errors by `--strict`:");
+    for (kind, count) in &failed {
+        eprintln!("  {count:>6}  {}", kind.as_str());
+    }
+    let total: usize = failed.iter().map(|(_, count)| count).sum();
+    bail!("{total} warnings with `--strict`.")
+}
+
+// Write the overrides applied to the packages to a JSON file, sorted by package so that the file
+// only changes along with the overrides or the packages.
+fn write_override_log(packages: &[Package], filename: &Path) -> Result<()> {
+    let mut entries: Vec<OverrideLogEntry> = packages
+        .iter()
+        .filter_map(|package| package.metadata.get(OVERRIDE_KEY))
+        .map(|entry| {
+            OverrideLogEntry::deserialize(entry).expect("Log entries are stored as serialized")
+        })
+        .collect();
+    entries.sort_by(|a, b| (&a.package, &a.version).cmp(&(&b.package, &b.version)));
+    let mut data = serde_json::to_vec_pretty(&entries)?;
+    data.push(b'\n');
+    write_file(filename, &data)
+}
+
+// Check that every override in the configuration was applied to a package, across all projects.
+fn check_overrides_matched(packages: &[Package], overrides: &Overrides) -> Result<()> {
+    let matched: HashSet<&str> = packages
+        .iter()
+        .filter_map(|package| package.metadata.get(OVERRIDE_KEY)?.get("key")?.as_str())
+        .collect();
+    let mut unmatched: Vec<&str> = overrides
+        .keys()
+        .map(String::as_str)
+        .filter(|key| !matched.contains(key))
+        .collect();
+    if unmatched.is_empty() {
+        return Ok(());
+    }
+    unmatched.sort_unstable();
+    eprintln!("Overrides that do not apply to any package:");
+    for key in unmatched {
+        eprintln!("    {key}");
+    }
+    bail!("The configuration has overrides that do not apply to any package.")
+}
+
+// The aggregates printed by the `stats` subcommand.
+#[derive(Serialize)]
+struct StatsSummary<'a> {
+    components: usize,
+    unique_origins: usize,
+    licenses: Vec<Ranked<'a>>,
+    origin_domains: Vec<Ranked<'a>>,
+    // The number of components in each license category.
+    license_categories: BTreeMap<&'static str, usize>,
+    // How the copyrights were found, which is unknown for an existing license file.
+    copyright_sources: Option<BTreeMap<&'static str, usize>>,
+}
+
+#[derive(Serialize)]
+struct Ranked<'a> {
+    name: &'a str,
+    count: usize,
+}
+
+// Print the most common licenses and origin domains of the records, the number in each license
+// category, and how their copyrights were found when they were generated.
+fn print_stats(
+    records: &[Record],
+    stats: Option<&Stats>,
+    format: StatsFormat,
+    config: &Config,
+) -> Result<()> {
+    // Rank the most common values, breaking ties by the value so the output is stable.
+    fn top<'a>(values: impl Iterator<Item = &'a str>) -> Vec<Ranked<'a>> {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for value in values {
+            *counts.entry(value).or_default() += 1;
+        }
+        let mut ranked: Vec<Ranked> = counts
+            .into_iter()
+            .map(|(name, count)| Ranked { name, count })
+            .collect();
+        ranked.sort_by(|a, b| b.count.cmp(&a.count).then(a.name.cmp(b.name)));
+        ranked.truncate(STATS_TOP_COUNT);
+        ranked
+    }
+
+    let origins: Vec<Url> = records
+        .iter()
+        .filter_map(|record| Url::parse(&record.origin).ok())
+        .collect();
+    let (categories, _) = license_categories(records, config);
+    let summary = StatsSummary {
+        components: records.len(),
+        unique_origins: records
+            .iter()
+            .map(|record| &record.origin)
+            .collect::<HashSet<_>>()
+            .len(),
+        licenses: top(records.iter().map(|record| record.license.as_str())),
+        origin_domains: top(origins.iter().filter_map(Url::host_str)),
+        license_categories: LicenseCategory::ALL
+            .into_iter()
+            .map(|category| {
+                (
+                    category.as_str(),
+                    categories.get(&category).map_or(0, Vec::len),
+                )
+            })
+            .collect(),
+        copyright_sources: stats.map(|stats| {
+            BTreeMap::from([
+                ("detected", stats.detected),
+                ("author_fallback", stats.fallback),
+                ("reused", stats.reused),
+            ])
+        }),
+    };
+    if format == StatsFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        return Ok(());
+    }
+    println!("Components: {}", summary.components);
+    println!("Unique origins: {}", summary.unique_origins);
+    println!("Most common licenses:");
+    for Ranked { name, count } in &summary.licenses {
+        println!("  {count:>6}  {name}");
+    }
+    println!("Most common origin domains:");
+    for Ranked { name, count } in &summary.origin_domains {
+        println!("  {count:>6}  {name}");
+    }
+    println!("License categories:");
+    for category in LicenseCategory::ALL {
+        let name = category.as_str();
+        println!("  {:>6}  {name}", summary.license_categories[name]);
+    }
+    if let Some(stats) = stats {
+        println!("Copyright sources:");
+        println!("  {:>6}  detected in the package files", stats.detected);
+        println!("  {:>6}  author fallback", stats.fallback);
+        if stats.reused > 0 {
+            println!("  {:>6}  reused from the license file", stats.reused);
+        }
+    }
+    Ok(())
+}
+
+// Counts of how the details of the packages were found.
+#[derive(Default)]
+struct Stats {
+    packages: usize,
+    detected: usize,
+    fallback: usize,
+    overridden: usize,
+    reused: usize,
+    // Records merged into another with the same details.
+    merged: usize,
+    // The files the details were read from, for `--emit-make-deps`.
+    inputs: BTreeSet<PathBuf>,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Self) {
+        self.packages += other.packages;
+        self.detected += other.detected;
+        self.fallback += other.fallback;
+        self.overridden += other.overridden;
+        self.reused += other.reused;
+        self.inputs.extend(other.inputs.iter().cloned());
+    }
+}
+
+// Format a Makefile rule making the license file depend on the given files, with an empty rule for
+// each of them so that Make does not fail when one is removed.
+fn make_deps(dest: &Path, inputs: &BTreeSet<PathBuf>) -> String {
+    // Paths under the current directory are shown relative to it, as Make sees them.
+    let current = env::current_dir().unwrap_or_default();
+    let inputs: Vec<String> = inputs
+        .iter()
+        .map(|path| make_escape(relative_path(path, &current)))
+        .collect();
+    let mut text = make_escape(dest) + ":";
+    for input in &inputs {
+        text += " \\\n  ";
+        text += input;
+    }
+    text += "\n";
+    for input in &inputs {
+        text += &format!("\n{input}:\n");
+    }
+    text
+}
+
+// Escape the characters that are special in the targets and prerequisites of a Makefile rule.
+fn make_escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '$' => escaped.push_str("$$"),
+            ' ' | '#' | ':' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Collect the manifests of the projects under a directory, in a stable order. The directories
+// within a project are not searched, as any packages there belong to its workspace or are vendored.
+fn find_projects(directory: &Path, manifests: &mut Vec<PathBuf>) -> Result<()> {
+    let manifest = directory.join("Cargo.toml");
+    if manifest.is_file() {
+        manifests.push(manifest);
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(directory)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .with_context(|| format!("Could not read directory {directory:?}"))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        // Symlinked directories are not followed, to avoid loops.
+        let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+        if is_dir && !SKIPPED_DIRECTORIES.iter().any(|skipped| name == *skipped) {
+            find_projects(&entry.path(), manifests)?;
+        }
+    }
+    Ok(())
+}
+
+// Run the package pipeline for each of the projects, using up to `--jobs` threads shared between
+// the projects and the copyright scanning within them. The resulting packages are merged in the
+// order the projects were given, regardless of which finished first, and failures are collected so
+// that every failing project is reported.
+fn build_all_packages(
+    args: &Args,
+    config: &Config,
+    reused: &ReusedCopyrights,
+) -> Result<(Vec<Package>, Stats, DependencyGraph)> {
+    let manifest_paths = args.manifest_paths();
+    let jobs = args.jobs().min(manifest_paths.len());
+    let scan_jobs = (args.jobs() / jobs).max(1);
+    let results = parallel_map(&manifest_paths, jobs, |&manifest_path| {
+        set_warning_prefix(manifest_path.filter(|_| manifest_paths.len() > 1));
+        build_packages(args, config, reused, manifest_path, scan_jobs)
+    });
+    set_warning_prefix(None);
+
+    let mut seen = HashSet::new();
+    let mut packages = Vec::new();
+    let mut stats = Stats::default();
+    let mut graph = DependencyGraph::default();
+    let mut errors = Vec::new();
+    for (result, manifest_path) in results.into_iter().zip(&manifest_paths) {
+        match result {
+            Ok((found, found_stats, found_graph)) => {
+                stats.add(&found_stats);
+                graph.merge(found_graph);
+                packages.extend(
+                    found
+                        .into_iter()
+                        .filter(|package| seen.insert(package.id.clone())),
+                );
+            }
+            Err(error) if manifest_paths.len() == 1 => return Err(error),
+            Err(error) => errors.push(format!("{:?}: {error:#}", manifest_path.unwrap())),
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "Could not process {} of the projects:\n{}",
+            errors.len(),
+            errors.join("\n")
+        );
+    }
+    Ok((packages, stats, graph))
+}
+
+// Apply the function to each item using up to the given number of threads, returning the results
+// in the order of the items. With a single thread, the items are processed in order on the current
+// thread. The worker threads inherit the warning prefix of the current thread.
+fn parallel_map<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let prefix = WARNING_PREFIX.with_borrow(String::clone);
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    WARNING_PREFIX.set(prefix.clone());
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break;
+                        };
+                        results.push((index, f(item)));
+                    }
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Worker thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// Run `cargo metadata` for a single project and collect the details of its dependencies, scanning
+// for copyrights with up to the given number of threads.
+fn build_packages(
+    args: &Args,
+    config: &Config,
+    reused: &ReusedCopyrights,
+    manifest_path: Option<&Path>,
+    jobs: usize,
+) -> Result<(Vec<Package>, Stats, DependencyGraph)> {
+    let command = metadata_command(args, manifest_path);
+    let metadata = command.exec().with_context(|| {
+        let command = display_command(&command.cargo_command());
+        format!("Running `{command}` failed")
+    })?;
+
+    let project_license = metadata
+        .root_package()
+        .map(|root| (root.name.clone(), root.license.clone()));
+    let workspace_origin = config.workspace_origin.clone().or_else(|| {
+        let root = metadata.root_package()?;
+        root.repository.clone()
+    });
+    let resolve = metadata
+        .resolve
+        .context("Metadata is missing a dependency tree")?;
+    let dependents = args
+        .annotate_dependents
+        .then(|| find_dependents(&resolve, &metadata.workspace_members, &metadata.packages));
+    // The paths to the dependencies are found before the tree is filtered, to report how any
+    // packages from denied sources are reached.
+    let parents = (!args.deny_source.is_empty())
+        .then(|| dependency_parents(&resolve, &metadata.workspace_members));
+    let edges = args.emit_dot.is_some().then(|| dependency_edges(&resolve));
+    let filtered = filter_deps(resolve, &metadata.workspace_members, args.depth_limit());
+    let mut graph = edges.map_or_else(DependencyGraph::default, |edges| {
+        DependencyGraph::new(
+            edges,
+            &filtered,
+            &metadata.workspace_members,
+            &metadata.packages,
+        )
+    });
+    if let Some(parents) = parents {
+        let members = &metadata.workspace_members;
+        check_denied_sources(args, &filtered, &metadata.packages, members, &parents)?;
+    }
+    // Overrides from the member manifests only apply where the configuration has none.
+    let mut overrides = config.overrides.clone();
+    let mut sources = HashMap::new();
+    for (manifest, embedded) in embedded_overrides(&metadata.packages, &metadata.workspace_members)?
+    {
+        for (key, opts) in embedded {
+            if let Entry::Vacant(entry) = overrides.entry(key) {
+                sources.insert(entry.key().clone(), manifest.clone());
+                entry.insert(opts);
+            }
+        }
+    }
+    // The lockfile and the workspace manifests decide which dependencies there are.
+    let mut inputs: BTreeSet<PathBuf> = metadata
+        .packages
+        .iter()
+        .filter(|package| metadata.workspace_members.contains(&package.id))
+        .map(|package| package.manifest_path.clone().into_std_path_buf())
+        .collect();
+    for filename in ["Cargo.lock", "Cargo.toml"] {
+        let path = metadata.workspace_root.join(filename);
+        if path.is_file() {
+            inputs.insert(path.into_std_path_buf());
+        }
+    }
+    let members: &[PackageId] = if args.include_workspace_members {
+        &metadata.workspace_members
+    } else {
+        &[]
+    };
+    let mut packages = lookup_deps(filtered, metadata.packages, members);
+    if !args.source_filter.is_empty() {
+        packages.retain(|package| SourceKind::matches(&args.source_filter, package));
+    }
+    if let Some(dependents) = dependents {
+        for package in &mut packages {
+            let names = dependents.get(&package.id).into_iter().flatten();
+            let names = names.map(String::as_str).collect::<Vec<_>>().join(", ");
+            set_metadata(package, DEPENDENTS_KEY, Value::String(names));
+        }
+    }
+    if let Some(origin) = workspace_origin {
+        for package in packages
+            .iter_mut()
+            .filter(|package| members.contains(&package.id))
+        {
+            package.repository = Some(origin.clone());
+        }
+    }
+    let overridden = rewrite_packages(&mut packages, &overrides, config)?;
+    if args.check_spdx_compatibility {
+        let (name, license) = project_license.with_context(|| {
+            "`--check-spdx-compatibility` needs a root package, which a virtual workspace lacks"
+        })?;
+        let license = license.with_context(|| format!("Root package {name} has no license"))?;
+        let dependencies = packages
+            .iter()
+            .filter(|package| !members.contains(&package.id));
+        check_spdx_compatibility(&license, dependencies, config);
+    }
+    graph.mark_included(&packages);
+    let root = match &args.workspace_root {
+        Some(root) => fs::canonicalize(root)
+            .with_context(|| format!("Could not find workspace root {root:?}"))?,
+        None => metadata.workspace_root.into_std_path_buf(),
+    };
+    if args.verbose {
+        for package in &packages {
+            let manifest = override_key(&overrides, package).and_then(|key| sources.get(key));
+            if let Some(manifest) = manifest {
+                let manifest = relative_path(manifest, &root).display();
+                eprintln!("Applied the override for {} from {manifest}", package.name);
+            }
+        }
+    }
+    fixup_names(&mut packages, &root)?;
+    if args.verbose {
+        for package in &packages {
+            if let Some(index_name) = package.metadata.get(INDEX_NAME_KEY) {
+                let index_name = index_name.as_str().unwrap_or_default();
+                eprintln!("Renamed {index_name} to {} from its manifest", package.name);
+            }
+        }
+    }
+    if config.scan_vendored {
+        let mut with_vendored = Vec::with_capacity(packages.len());
+        for package in packages {
+            let vendored = vendored_packages(&package, &overrides, config)?;
+            with_vendored.push(package);
+            with_vendored.extend(vendored);
+        }
+        packages = with_vendored;
+    }
+    let mut stats = Stats {
+        packages: packages.len(),
+        overridden,
+        ..Stats::default()
+    };
+    if !args.no_copyright {
+        let scanner = CopyrightScanner::new(args, config, &overrides, root);
+        (stats.detected, stats.reused) =
+            lookup_all_copyrights(&mut packages, &scanner, reused, jobs)?;
+        stats.fallback = packages.len() - stats.detected - stats.reused;
+        let files_read = scanner.files_read.into_inner();
+        inputs.append(&mut files_read.unwrap_or_else(PoisonError::into_inner));
+    }
+    stats.inputs = inputs;
+    Ok((packages, stats, graph))
+}
+
+// Set up the `cargo metadata` command for a project. When no cargo path is given, this uses the
+// `CARGO` environment variable if it is set.
+fn metadata_command(args: &Args, manifest_path: Option<&Path>) -> MetadataCommand {
+    let mut command = MetadataCommand::new();
+    command.verbose(true);
+    if let Some(cargo_path) = &args.cargo_path {
+        command.cargo_path(cargo_path);
+    }
+    command.other_options(args.cargo_arg.clone());
+    if let Some(manifest_path) = manifest_path {
+        command.manifest_path(manifest_path);
+    }
+    command
+}
+
+// Format a command line for display, quoting any arguments that the shell would otherwise split or
+// interpret.
+fn display_command(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+            {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Given a list of package IDs, look up the corresponding entry from the package list and return an
+// array of the results, in the order of the package list.
+fn lookup_deps(
+    package_ids: HashMap<PackageId, Relation>,
+    packages: Vec<Package>,
+    members: &[PackageId],
+) -> Vec<Package> {
+    packages
+        .into_iter()
+        .filter_map(|mut package| {
+            // Local packages are skipped, except for the workspace members to include, which are
+            // treated as direct dependencies.
+            let member = members.contains(&package.id);
+            if package.source.is_none() && !member {
+                return None;
+            }
+            let relation = package_ids
+                .get(&package.id)
+                .copied()
+                .or(member.then_some(Relation::Direct))?;
+            let relation = serde_json::to_value(relation).expect("Relation is serializable");
+            set_metadata(&mut package, RELATION_KEY, relation);
+            Some(package)
+        })
+        .collect()
+}
+
+// Filter the list of dependencies to exclude those that would not be distributed in a built
+// artifact. i.e. Skip those dependencies that are only used as build or dev dependencies. Each
+// remaining dependency is marked with whether it is a direct dependency of the root or a workspace
+// member. If a maximum depth is given, dependencies further than that from the roots are skipped.
+//
+// Optional dependencies need no special handling here, as the resolved tree from `cargo metadata`
+// only has edges for those activated by the enabled features. Any that are not activated are left
+// out, while `--cargo-arg=--features=...` or `--cargo-arg=--all-features` includes them.
+fn filter_deps(
+    resolve: Resolve,
+    members: &[PackageId],
+    max_depth: Option<u32>,
+) -> HashMap<PackageId, Relation> {
+    let deps: HashMap<_, _> = resolve
+        .nodes
+        .into_iter()
+        .map(|node| (node.id.clone(), node))
+        .collect();
+
+    let mut filter = DepFilter {
+        deps: &deps,
+        max_depth,
+        packages: HashMap::new(),
+    };
+    filter.filter_deps_rec(resolve.root.as_ref(), members);
+    filter
+        .packages
         .into_iter()
-        .flat_map(|(record, names)| reduce_names(record, names))
+        .map(|(id, (relation, _))| (id, relation))
+        .collect()
+}
+
+struct DepFilter<'a> {
+    deps: &'a HashMap<PackageId, Node>,
+    max_depth: Option<u32>,
+    // The relation of each package found, along with the shallowest depth it was reached at.
+    packages: HashMap<PackageId, (Relation, u32)>,
+}
+
+impl DepFilter<'_> {
+    fn filter_deps_rec(&mut self, node: Option<&PackageId>, members: &[PackageId]) {
+        match node {
+            Some(node) => self.filter_node_deps_rec(node, Relation::Direct, 1),
+            None => {
+                // We're dealing with a workspace crate, so we iterate over all dependencies. Only
+                // the members are roots for the purpose of limiting the depth.
+                for pkg in self.deps.keys() {
+                    if members.contains(pkg) {
+                        self.filter_node_deps_rec(pkg, Relation::Direct, 1);
+                    } else if self.max_depth.is_none() {
+                        self.filter_node_deps_rec(pkg, Relation::Transitive, 1);
+                    }
+                }
+            }
+        }
+    }
+
+    // Recursively collect the normal dependencies of the node, which are marked with the given
+    // relation and depth. Packages already found are only revisited if they are now reached at a
+    // shallower depth, which could include more of their dependencies.
+    fn filter_node_deps_rec(&mut self, node: &PackageId, relation: Relation, depth: u32) {
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return;
+        }
+        let root = self.deps.get(node).unwrap();
+        for node in &root.deps {
+            if is_normal_dep(&node.dep_kinds) {
+                let expand = match self.packages.entry(node.pkg.clone()) {
+                    Entry::Occupied(mut entry) => {
+                        let (existing, existing_depth) = entry.get_mut();
+                        *existing = relation.min(*existing);
+                        let shallower = depth < *existing_depth;
+                        *existing_depth = depth.min(*existing_depth);
+                        shallower
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert((relation, depth));
+                        true
+                    }
+                };
+                if expand {
+                    self.filter_node_deps_rec(&node.pkg, Relation::Transitive, depth + 1);
+                }
+            }
+        }
+    }
+}
+
+// Find the parent of each package on the shortest path to it from the workspace members, following
+// only normal dependencies.
+fn dependency_parents(resolve: &Resolve, members: &[PackageId]) -> HashMap<PackageId, PackageId> {
+    let nodes: HashMap<_, _> = resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+    let mut parents = HashMap::new();
+    let mut queue: VecDeque<&PackageId> = members.iter().collect();
+    while let Some(id) = queue.pop_front() {
+        let Some(node) = nodes.get(id) else {
+            continue;
+        };
+        for dep in &node.deps {
+            if is_normal_dep(&dep.dep_kinds)
+                && !members.contains(&dep.pkg)
+                && !parents.contains_key(&dep.pkg)
+            {
+                parents.insert(dep.pkg.clone(), id.clone());
+                queue.push_back(&dep.pkg);
+            }
+        }
+    }
+    parents
+}
+
+// Fail if any of the dependencies come from a denied kind of source, listing each one along with
+// the path to it from the workspace. The workspace members themselves are not checked.
+fn check_denied_sources(
+    args: &Args,
+    deps: &HashMap<PackageId, Relation>,
+    packages: &[Package],
+    members: &[PackageId],
+    parents: &HashMap<PackageId, PackageId>,
+) -> Result<()> {
+    let by_id: HashMap<&PackageId, &Package> = packages
+        .iter()
+        .map(|package| (&package.id, package))
+        .collect();
+    let mut denied: Vec<&Package> = packages
+        .iter()
+        .filter(|package| deps.contains_key(&package.id) && !members.contains(&package.id))
+        .filter(|package| SourceKind::matches(&args.deny_source, package))
+        .filter(|package| {
+            args.source_filter.is_empty() || SourceKind::matches(&args.source_filter, package)
+        })
+        .collect();
+    if denied.is_empty() {
+        return Ok(());
+    }
+    denied.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    for package in &denied {
+        let mut path = vec![format!("{} {}", package.name, package.version)];
+        let mut id = &package.id;
+        while let Some(parent) = parents.get(id) {
+            let name = by_id
+                .get(parent)
+                .map_or(&parent.repr, |parent| &parent.name);
+            path.push(name.clone());
+            id = parent;
+        }
+        path.reverse();
+        report!(
+            "Package {} {} is from a {} source, reached through {}",
+            package.name,
+            package.version,
+            SourceKind::of(package).as_str(),
+            path.join(" -> ")
+        );
+    }
+    bail!("{} dependencies are from denied sources.", denied.len())
+}
+
+// Find the names of the workspace members that depend on each package, directly or through other
+// packages, following only normal dependencies.
+fn find_dependents(
+    resolve: &Resolve,
+    members: &[PackageId],
+    packages: &[Package],
+) -> HashMap<PackageId, BTreeSet<String>> {
+    let nodes: HashMap<_, _> = resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+    let mut dependents: HashMap<PackageId, BTreeSet<String>> = HashMap::new();
+    for member in members {
+        let Some(package) = packages.iter().find(|package| package.id == *member) else {
+            continue;
+        };
+        let mut seen = HashSet::new();
+        let mut stack = vec![member];
+        while let Some(id) = stack.pop() {
+            for dep in &nodes[id].deps {
+                if is_normal_dep(&dep.dep_kinds) && seen.insert(&dep.pkg) {
+                    let names = dependents.entry(dep.pkg.clone()).or_default();
+                    names.insert(package.name.clone());
+                    stack.push(&dep.pkg);
+                }
+            }
+        }
+    }
+    dependents
+}
+
+// List the normal dependencies of each package in the tree, as pairs of the package and dependency.
+fn dependency_edges(resolve: &Resolve) -> Vec<(PackageId, PackageId)> {
+    resolve
+        .nodes
+        .iter()
+        .flat_map(|node| {
+            node.deps
+                .iter()
+                .filter(|dep| is_normal_dep(&dep.dep_kinds))
+                .map(|dep| (node.id.clone(), dep.pkg.clone()))
+        })
+        .collect()
+}
+
+// The graph of dependencies written by `--emit-dot`, keyed on the package IDs so that the graphs of
+// several projects can be merged.
+#[derive(Default)]
+struct DependencyGraph {
+    // The label and kind of each package.
+    nodes: BTreeMap<String, (String, GraphNode)>,
+    edges: BTreeSet<(String, String)>,
+}
+
+// The kinds of packages in the dependency graph, in increasing precedence when merging graphs.
+#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+enum GraphNode {
+    // Dependencies left out of the license data, such as by `--source-filter`.
+    Excluded,
+    Included,
+    Member,
+}
+
+impl DependencyGraph {
+    // Build the graph of the workspace members and the dependencies that passed the filters, with
+    // the edges between them. The dependencies start out as excluded until `mark_included`.
+    fn new(
+        edges: Vec<(PackageId, PackageId)>,
+        filtered: &HashMap<PackageId, Relation>,
+        members: &[PackageId],
+        packages: &[Package],
+    ) -> Self {
+        let nodes: BTreeMap<String, (String, GraphNode)> = packages
+            .iter()
+            .filter_map(|package| {
+                let kind = if members.contains(&package.id) {
+                    GraphNode::Member
+                } else if filtered.contains_key(&package.id) {
+                    GraphNode::Excluded
+                } else {
+                    return None;
+                };
+                let label = format!("{} {}", package.name, package.version);
+                Some((package.id.repr.clone(), (label, kind)))
+            })
+            .collect();
+        let edges = edges
+            .into_iter()
+            .filter(|(from, to)| nodes.contains_key(&from.repr) && nodes.contains_key(&to.repr))
+            .map(|(from, to)| (from.repr, to.repr))
+            .collect();
+        Self { nodes, edges }
+    }
+
+    // Mark the dependencies that remain in the license data.
+    fn mark_included(&mut self, packages: &[Package]) {
+        for package in packages {
+            if let Some((_, kind)) = self.nodes.get_mut(&package.id.repr) {
+                *kind = (*kind).max(GraphNode::Included);
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (id, (label, kind)) in other.nodes {
+            let node = self.nodes.entry(id).or_insert((label, kind));
+            node.1 = node.1.max(kind);
+        }
+        self.edges.extend(other.edges);
+    }
+
+    fn to_dot(&self) -> String {
+        let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut dot = String::from("digraph dependencies {\n    node [shape=box, style=filled];\n");
+        for (id, (label, kind)) in &self.nodes {
+            let color = match kind {
+                GraphNode::Member => "lightblue",
+                GraphNode::Included => "palegreen",
+                GraphNode::Excluded => "white",
+            };
+            dot += &format!(
+                "    {} [label={}, fillcolor={color}];\n",
+                quote(id),
+                quote(label)
+            );
+        }
+        for (from, to) in &self.edges {
+            dot += &format!("    {} -> {};\n", quote(from), quote(to));
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+fn is_normal_dep(kinds: &[DepKindInfo]) -> bool {
+    kinds.iter().any(|dep| dep.kind == DependencyKind::Normal)
+}
+
+// Translate the array of packages into an array of output records.
+fn build_records(packages: Vec<Package>, args: &Args, config: &Config) -> Result<Vec<Record>> {
+    let records = packages
+        .into_iter()
+        .map(|package| package_to_record(package, args, config))
+        .collect::<Result<Vec<_>>>()?;
+    let mut records = merge_records(records, args, config);
+    // The notes are added after merging the records, as they are keyed on the final component names.
+    if args.include_notes {
+        for record in &mut records {
+            let notes = config.annotations.get(&record.component);
+            record.notes = Some(notes.cloned().unwrap_or_default());
+        }
+        for component in config.annotations.keys() {
+            if !records.iter().any(|record| record.component == *component) {
+                warn!(
+                    Warning::UnmatchedAnnotation,
+                    "Annotation for {component:?} does not match any component"
+                );
+            }
+        }
+    }
+    Ok(records)
+}
+
+// Merge the records that differ only in their component names, and sort the result.
+fn merge_records(records: Vec<Record>, args: &Args, config: &Config) -> Vec<Record> {
+    let patterns = config
+        .name_reduction_patterns
+        .as_deref()
+        .unwrap_or(&DEFAULT_NAME_REDUCTION_PATTERNS);
+    let mut result: Vec<Record> = collect_record_sets(records)
+        .into_iter()
+        .flat_map(|(record, names)| reduce_names(record, names, patterns))
         .collect();
-    result.sort();
+    sort_records(&mut result, args);
     result
 }
 
+fn sort_records(records: &mut [Record], args: &Args) {
+    if args.group_by_license {
+        records.sort_by(|a, b| a.license.cmp(&b.license).then_with(|| a.cmp(b)));
+    } else if !args.no_sort {
+        records.sort();
+    }
+}
+
+// Add the records of the existing license file whose components are not among the generated
+// records, sorted in with them, and return the names of those components.
+fn preserve_extra_records(
+    records: &mut Vec<Record>,
+    dest: &Path,
+    headers: &Headers,
+    args: &Args,
+) -> Result<Vec<String>> {
+    let generated: HashSet<String> = records
+        .iter()
+        .map(|record| record.component.clone())
+        .collect();
+    let columns = |record: &Record| -> Vec<&str> {
+        record.fields().into_iter().map(|(name, _)| name).collect()
+    };
+    let expected = records.first().map(columns);
+    let mut preserved = Vec::new();
+    for record in read_records(dest, headers)? {
+        if generated.contains(&record.component) {
+            continue;
+        }
+        if expected
+            .as_ref()
+            .is_some_and(|expected| *expected != columns(&record))
+        {
+            bail!(
+                "Could not preserve the row for {:?}, as the columns of {dest:?} differ from those \
+                 generated",
+                record.component
+            );
+        }
+        preserved.push(record.component.clone());
+        records.push(record);
+    }
+    sort_records(records, args);
+    Ok(preserved)
+}
+
+// Check for different records that ended up with the same component name, either through name
+// reduction or manifest renames, as that confuses any consumer keyed on the component.
+fn check_collisions(records: &[Record]) {
+    for (component, records) in collisions(records) {
+        warn!(
+            Warning::Collision,
+            "Component {component:?} has conflicting records:"
+        );
+        for record in records {
+            report!("    {},{}", record.origin, record.license);
+        }
+    }
+}
+
+// Group the records by component name, keeping the groups whose records differ in origin or
+// license.
+fn collisions(records: &[Record]) -> BTreeMap<&str, Vec<&Record>> {
+    let mut components: BTreeMap<&str, Vec<&Record>> = BTreeMap::new();
+    for record in records {
+        components
+            .entry(&record.component)
+            .or_default()
+            .push(record);
+    }
+    components.retain(|_, records| {
+        let first = records[0];
+        records
+            .iter()
+            .any(|record| record.origin != first.origin || record.license != first.license)
+    });
+    components
+}
+
+// Check for several records with the same component name and origin, such as from different versions
+// of a crate with different copyrights, as downstream tools may key on both.
+fn check_duplicate_origins(records: &[Record], error: bool) -> Result<()> {
+    let mut counts: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for record in records {
+        *counts
+            .entry((&record.component, &record.origin))
+            .or_default() += 1;
+    }
+    let mut duplicates = false;
+    for ((component, origin), count) in counts {
+        if count > 1 {
+            warn!(
+                Warning::DuplicateOrigin,
+                "Component {component:?} has {count} records with the origin {origin}"
+            );
+            duplicates = true;
+        }
+    }
+    if duplicates && error {
+        bail!("Records in the license data have duplicate components and origins.")
+    } else {
+        Ok(())
+    }
+}
+
+// Check that the host of each origin URL resolves in DNS, which catches broken URLs without making
+// any HTTP requests.
+fn validate_urls(records: &[Record], args: &Args) {
+    let hosts: BTreeSet<String> = records
+        .iter()
+        .filter_map(|record| Url::parse(&record.origin).ok()?.host_str().map(Into::into))
+        .collect();
+    let hosts: Vec<String> = hosts.into_iter().collect();
+    let resolved = parallel_map(&hosts, args.jobs(), |host| {
+        (host.as_str(), 443)
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.next().is_some())
+    });
+    for (host, resolved) in hosts.iter().zip(resolved) {
+        if !resolved {
+            let components: Vec<&str> = records
+                .iter()
+                .filter(|record| {
+                    Url::parse(&record.origin).is_ok_and(|url| url.host_str() == Some(host))
+                })
+                .map(|record| record.component.as_str())
+                .collect();
+            warn!(
+                Warning::UnresolvedHost,
+                "Host {host} does not resolve, used by {}",
+                components.join(", ")
+            );
+        }
+    }
+}
+
 // Extract the output record fields from a input package.
-fn package_to_record(package: Package) -> Record {
+fn package_to_record(package: Package, args: &Args, config: &Config) -> Result<Record> {
     // These are fixed up in `rewrite_packages` so we can just `unwrap` with impunity here.
-    let origin = package.repository.as_deref().unwrap().to_string();
-    let license = package.license.as_deref().unwrap().replace('/', " OR ");
-    let component = package.name;
-    let copyright = package
-        .metadata
-        .get(COPYRIGHT_KEY)
-        .unwrap_or_else(|| panic!("Copyright for {component:?} should have been set"))
-        .as_str()
-        .expect("Copyright is always set to a string")
-        .into();
-    Record {
+    let repository = package.repository.as_deref().unwrap();
+    let origin = normalize_origin(repository).with_context(|| {
+        // Point at where the value needs fixing.
+        let source = match package.metadata.get(OVERRIDE_KEY) {
+            Some(entry) if entry["fields"].get("origin").is_some() => {
+                format!("the override {}", entry["key"])
+            }
+            _ => "the package metadata".into(),
+        };
+        format!(
+            "Package {} has an invalid origin URL {repository:?} from {source}",
+            package.name
+        )
+    })?;
+    let mut license = package.license.as_deref().unwrap().replace('/', " OR ");
+    if config.license_expression_normalize_or {
+        license = license.replace(" | ", " OR ").replace('|', " OR ");
+    }
+    if !config.aliases.is_empty() {
+        license = RE_LICENSE_TOKEN
+            .replace_all(&license, |captures: &regex::Captures| {
+                let id = &captures[0];
+                config.aliases.get(id).map_or(id, String::as_str).to_owned()
+            })
+            .into_owned();
+    }
+    let group = args
+        .include_groups
+        .then(|| package_groups(&package, &config.dependency_groups));
+    let download = args
+        .include_download
+        .then(|| download_url(&package, &origin, config));
+    // This is only set when the manifest renames the package.
+    let index_name = args.include_index_name.then(|| {
+        package
+            .metadata
+            .get(INDEX_NAME_KEY)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .into()
+    });
+    let component = match package.source.as_ref().and_then(git_tag) {
+        Some(tag) if args.git_tag_as_version => format!("{}@{tag}", package.name),
+        _ => package.name,
+    };
+    let copyright = (!args.no_copyright).then(|| {
+        package
+            .metadata
+            .get(COPYRIGHT_KEY)
+            .unwrap_or_else(|| panic!("Copyright for {component:?} should have been set"))
+            .as_str()
+            .expect("Copyright is always set to a string")
+            .into()
+    });
+    let relation = args.include_relation.then(|| {
+        let relation = package.metadata[RELATION_KEY].clone();
+        serde_json::from_value(relation).expect("Relation is always set")
+    });
+    let dependents = args.annotate_dependents.then(|| {
+        package.metadata[DEPENDENTS_KEY]
+            .as_str()
+            .expect("Dependents are always set to a string")
+            .into()
+    });
+    // Descriptions may span several paragraphs, so they are collapsed onto one line.
+    let description = args.include_description.then(|| {
+        let description = package.description.as_deref().unwrap_or_default();
+        let description = description.split_whitespace().collect::<Vec<_>>().join(" ");
+        let max_length = config
+            .max_description_length
+            .unwrap_or(DEFAULT_MAX_DESCRIPTION_LENGTH);
+        truncate_text(description, max_length)
+    });
+    Ok(Record {
         component,
         origin,
         license,
         copyright,
+        relation,
+        group,
+        dependents,
+        description,
+        download,
+        index_name,
+        notes: None,
+    })
+}
+
+// Strip the protocol prefix, as in `sparse+`, and any trailing `/` from the index URL of a registry.
+fn registry_index(url: &str) -> &str {
+    strip_suffix(url.split_once('+').map_or(url, |(_, url)| url), "/")
+}
+
+fn is_from_crates_io(package: &Package) -> bool {
+    package
+        .source
+        .as_ref()
+        .is_some_and(|source| CRATES_IO_INDEXES.contains(&registry_index(&source.repr)))
+}
+
+// The index URL of the registry a package comes from, when that is not crates.io.
+fn alternative_registry(package: &Package) -> Option<&str> {
+    let source = &package.source.as_ref()?.repr;
+    if !source.starts_with("registry+") && !source.starts_with("sparse+") {
+        return None;
+    }
+    let index = registry_index(source);
+    (!CRATES_IO_INDEXES.contains(&index)).then_some(index)
+}
+
+// The name of a registry in messages, from the `[registry_names]` table or else its index URL.
+fn registry_name<'a>(index: &'a str, names: &'a RegistryNames) -> &'a str {
+    names
+        .iter()
+        .find(|(registry, _)| registry_index(registry) == index)
+        .map_or(index, |(_, name)| name)
+}
+
+// Build the URL to download the exact artifact of a package from its source, without any network
+// access. This is empty for local packages and for registries without a configured download URL.
+fn download_url(package: &Package, origin: &str, config: &Config) -> String {
+    let Some(source) = &package.source else {
+        return String::new();
+    };
+    if let Some(git) = source.repr.strip_prefix("git+") {
+        // The fragment holds the commit the dependency is locked to.
+        return match git.split_once('#') {
+            Some((_, rev)) => format!("{origin}/tree/{rev}"),
+            None => String::new(),
+        };
+    }
+    let index = registry_index(&source.repr);
+    if CRATES_IO_INDEXES.contains(&index) {
+        return format!(
+            "https://crates.io/api/v1/crates/{}/{}/download",
+            package.name, package.version
+        );
+    }
+    match config
+        .registry_downloads
+        .iter()
+        .find(|(registry, _)| registry_index(registry) == index)
+    {
+        Some((_, template)) => template
+            .replace("{name}", &package.name)
+            .replace("{version}", &package.version.to_string()),
+        None => {
+            warn!(
+                Warning::MissingDownload,
+                "Package {} is from a registry without a download URL: {}",
+                package.name,
+                registry_name(index, &config.registry_names)
+            );
+            String::new()
+        }
+    }
+}
+
+// Extract the tag from a git source, like `git+https://github.com/org/repo?tag=v1.2.3#<hash>`.
+fn git_tag(source: &Source) -> Option<String> {
+    let url = Url::parse(source.repr.strip_prefix("git+")?).ok()?;
+    url.query_pairs()
+        .find(|(key, _)| key == "tag")
+        .map(|(_, tag)| tag.into_owned())
+}
+
+// List the groups containing the package, matched either by name or by name and version as with
+// overrides.
+fn package_groups(package: &Package, groups: &DependencyGroups) -> String {
+    let versioned = format!("{}-{}", package.name, package.version);
+    groups
+        .iter()
+        .filter(|(_, names)| {
+            names
+                .iter()
+                .any(|name| *name == package.name || *name == versioned)
+        })
+        .map(|(group, _)| group.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Normalize trivial variations in an origin URL, so that records differing only in the URL
+// spelling are collected together: a missing scheme is added and upgraded to HTTPS, the host is
+// lowercased, and any trailing `.git` or `/`, query string, or fragment is removed.
+fn normalize_origin(origin: &str) -> Result<String> {
+    // The URL parser would quietly trim or percent-encode these.
+    if origin.contains(char::is_whitespace) {
+        bail!("URLs cannot contain whitespace");
+    }
+    let mut url = match Url::parse(origin) {
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            if !starts_with_host(origin) {
+                bail!("URLs must start with a scheme or host name");
+            }
+            Url::parse(&format!("https://{origin}"))?
+        }
+        url => url?,
+    };
+    if url.scheme() == "http" {
+        // This can only fail when changing between special and non-special schemes.
+        let _ = url.set_scheme("https");
+    }
+    // A trailing `/` may also follow the `.git`.
+    let path = strip_git(strip_suffix(url.path(), "/")).to_owned();
+    url.set_path(&path);
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok(strip_suffix(url.as_str(), "/").into())
+}
+
+// Check if a URL without a scheme starts with a host name, like `github.com/org/repo`, rather than
+// being a path or a typo like `https//github.com`.
+fn starts_with_host(url: &str) -> bool {
+    let host = url.split(['/', ':']).next().unwrap_or_default();
+    host.contains('.')
+        && !host.starts_with('.')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+// Collect the given records into sets having identical details except for the component names, which
+// are extracted into the list of names. Both the sets and the names are kept in the order they
+// first appear.
+fn collect_record_sets(records: Vec<Record>) -> Vec<(Record, Vec<String>)> {
+    // Sort the records on their details, keeping the original order among equal details, so that
+    // nearly identical records that differ only in the component names become adjacent and can be
+    // merged in a single pass.
+    let mut records: Vec<(Record, usize, String)> = records
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut record)| {
+            let name = take(&mut record.component);
+            (record, index, name)
+        })
+        .collect();
+    records.sort_unstable();
+    let mut sets: Vec<(usize, Record, Vec<String>)> = Vec::new();
+    for (record, index, name) in records {
+        match sets.last_mut() {
+            Some((_, last, names)) if *last == record => {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+            _ => sets.push((index, record, vec![name])),
+        }
+    }
+    sets.sort_unstable_by_key(|(index, _, _)| *index);
+    sets.into_iter()
+        .map(|(_, record, names)| (record, names))
+        .collect()
+}
+
+// This "rehydrates" the record that is missing a component name into potentially multiple records
+// using the set of component names, while attempting to reduce the set down to a single entry.
+fn reduce_names(mut record: Record, names: Vec<String>, patterns: &[NamePattern]) -> Vec<Record> {
+    if names.len() == 1 {
+        record.component = names.into_iter().next().unwrap();
+        vec![record]
+    } else {
+        // If one of the component names matches the repository suffix, either exactly or through
+        // the first of the patterns that does, use just that one record.
+        if let Some((_, suffix)) = record.origin.rsplit_once('/') {
+            let candidates = std::iter::once(Some(suffix))
+                .chain(patterns.iter().map(|pattern| pattern.component(suffix)));
+            for name in candidates.flatten() {
+                if names.iter().any(|other| other == name) {
+                    record.component = name.into();
+                    return vec![record];
+                }
+            }
+        }
+        // There is no obvious component name to use as the primary, so just include them all.
+        names
+            .into_iter()
+            .map(|component| Record {
+                component,
+                ..record.clone()
+            })
+            .collect()
+    }
+}
+
+// Dump the resulting CSV table of records.
+fn output_table(
+    records: Vec<Record>,
+    headers: &Headers,
+    annotations: &Annotations,
+    include_hash: bool,
+    writer: impl Write,
+) -> Result<()> {
+    let hash = include_hash.then(|| records_hash(&records));
+    let mut csv = table_writer(writer);
+    if let Some(record) = records.first() {
+        write_headers(&mut csv, record, headers, &annotations.headers)?;
+    }
+    for record in records {
+        let notes = annotations.get(&record.component);
+        let fields = record.fields().into_iter().map(|(_, value)| value);
+        csv.write_record(fields.chain(notes))?;
+    }
+    csv.flush()?;
+    if let Some(hash) = hash {
+        let mut writer = csv.into_inner().map_err(|error| error.into_error())?;
+        writeln!(writer, "{HASH_PREFIX}{hash}")?;
+    }
+    Ok(())
+}
+
+// Compute the SHA-256 hash of the records, in hex. The records are hashed in sorted order as CSV
+// lines without the header line, so the hash does not depend on the order or column names used.
+fn records_hash(records: &[Record]) -> String {
+    let mut sorted: Vec<&Record> = records.iter().collect();
+    sorted.sort();
+    let mut csv = table_writer(Vec::new());
+    for record in sorted {
+        let fields = record.fields().into_iter().map(|(_, value)| value);
+        csv.write_record(fields)
+            .expect("Writing to memory cannot fail");
+    }
+    let data = csv.into_inner().expect("Writing to memory cannot fail");
+    hex::encode(Sha256::digest(data))
+}
+
+// Build an Excel workbook with the records on the first sheet, with the header row frozen, the
+// columns sized to their contents, and the origins as hyperlinks, and the number of components with
+// each license on the second sheet.
+fn xlsx_workbook(records: &[Record], headers: &Headers) -> Result<Vec<u8>> {
+    let mut components = xlsx::Sheet::default();
+    if let Some(record) = records.first() {
+        let names = record
+            .fields()
+            .into_iter()
+            .map(|(name, _)| headers.header(name));
+        components.add_row(names.map(|name| xlsx::Cell::Header(name.into())).collect());
+    }
+    for record in records {
+        let cells = record.fields().into_iter().map(|(name, value)| {
+            if name == "Origin" && (value.starts_with("https://") || value.starts_with("http://")) {
+                xlsx::Cell::Link(value)
+            } else {
+                xlsx::Cell::Text(value)
+            }
+        });
+        components.add_row(cells.collect());
+    }
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for record in records {
+        *counts.entry(&record.license).or_default() += 1;
+    }
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let mut licenses = xlsx::Sheet::default();
+    licenses.add_row(vec![
+        xlsx::Cell::Header("License".into()),
+        xlsx::Cell::Header("Components".into()),
+    ]);
+    for (license, count) in counts {
+        licenses.add_row(vec![
+            xlsx::Cell::Text(license.into()),
+            xlsx::Cell::Number(count),
+        ]);
+    }
+
+    xlsx::workbook(vec![("Components", components), ("Licenses", licenses)])
+}
+
+// The provenance of a license file, written to the file given by `--metadata-file`.
+#[derive(Deserialize, Serialize)]
+struct Provenance {
+    tool: String,
+    version: String,
+    // The time the file was written, in seconds since the Unix epoch.
+    generated_at: u64,
+    projects: Vec<ProjectProvenance>,
+    // A hash of the configuration file and the options given before the subcommand.
+    options_sha256: String,
+    records: usize,
+    // The packages in the lockfiles, written for `--incremental` to find the unchanged packages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    packages: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ProjectProvenance {
+    manifest_path: PathBuf,
+    lockfile_sha256: Option<String>,
+}
+
+impl Provenance {
+    fn new(args: &Args, records: usize) -> Result<Self> {
+        let projects = args
+            .manifest_paths()
+            .into_iter()
+            .map(|manifest_path| {
+                Ok(ProjectProvenance {
+                    manifest_path: manifest_path.unwrap_or(Path::new("Cargo.toml")).into(),
+                    lockfile_sha256: lockfile_hash(manifest_path)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let mut packages = BTreeSet::new();
+        if args.incremental {
+            for manifest_path in args.manifest_paths() {
+                packages.extend(locked_packages(manifest_path)?);
+            }
+        }
+        Ok(Self {
+            tool: env!("CARGO_PKG_NAME").into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            generated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            projects,
+            options_sha256: options_hash(args)?,
+            records,
+            packages: packages.into_iter().collect(),
+        })
+    }
+
+    fn read(filename: &Path) -> Result<Self> {
+        let data = fs::read(filename).with_context(|| format!("Could not read {filename:?}"))?;
+        serde_json::from_slice(&data).with_context(|| format!("Could not parse {filename:?}"))
+    }
+
+    // Check that the lockfile hashes in an existing provenance file match the current lockfiles.
+    fn check(filename: &Path, args: &Args) -> Result<()> {
+        let provenance = Self::read(filename)?;
+        let mut stale = false;
+        for manifest_path in args.manifest_paths() {
+            let path = manifest_path.unwrap_or(Path::new("Cargo.toml"));
+            let recorded = provenance
+                .projects
+                .iter()
+                .find(|project| project.manifest_path == path);
+            let Some(recorded) = recorded else {
+                println!("{filename:?} has no provenance for {path:?}.");
+                stale = true;
+                continue;
+            };
+            if recorded.lockfile_sha256 != lockfile_hash(manifest_path)? {
+                println!("The lockfile of {path:?} has changed since {filename:?} was written.");
+                stale = true;
+            }
+        }
+        if stale {
+            bail!("Current {filename:?} is stale.")
+        }
+        Ok(())
+    }
+}
+
+// Compute a hash of the configuration file and the options given before the subcommand, in hex.
+fn options_hash(args: &Args) -> Result<String> {
+    let mut options = Sha256::new();
+    match fs::read(args.config_filename()) {
+        Ok(config) => options.update(config),
+        Err(error) if error.kind() == ErrorKind::NotFound => (),
+        Err(error) => return Err(error).context("Could not read the configuration"),
+    }
+    for arg in env::args_os()
+        .skip(1)
+        .take_while(|arg| !arg.to_str().is_some_and(Commands::has_subcommand))
+    {
+        options.update(arg.as_encoded_bytes());
+        options.update([0]);
+    }
+    Ok(hex::encode(options.finalize()))
+}
+
+// Find the `Cargo.lock` for a project, which is the first one in the directory of the manifest or
+// its parents, as a workspace has a single lockfile at its root.
+fn find_lockfile(manifest_path: Option<&Path>) -> Result<Option<PathBuf>> {
+    let manifest_path = fs::canonicalize(manifest_path.unwrap_or(Path::new("Cargo.toml")))
+        .context("Could not find the manifest")?;
+    Ok(manifest_path
+        .ancestors()
+        .skip(1)
+        .map(|directory| directory.join("Cargo.lock"))
+        .find(|lockfile| lockfile.is_file()))
+}
+
+// Compute the SHA-256 hash of the `Cargo.lock` for a project, in hex.
+fn lockfile_hash(manifest_path: Option<&Path>) -> Result<Option<String>> {
+    let Some(lockfile) = find_lockfile(manifest_path)? else {
+        return Ok(None);
+    };
+    let data = fs::read(&lockfile).with_context(|| format!("Could not read {lockfile:?}"))?;
+    Ok(Some(hex::encode(Sha256::digest(data))))
+}
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+}
+
+// List the packages in the `Cargo.lock` for a project, in the form of `package_key`.
+fn locked_packages(manifest_path: Option<&Path>) -> Result<Vec<String>> {
+    let Some(lockfile) = find_lockfile(manifest_path)? else {
+        return Ok(Vec::new());
+    };
+    let data =
+        fs::read_to_string(&lockfile).with_context(|| format!("Could not read {lockfile:?}"))?;
+    let lockfile: Lockfile =
+        toml::from_str(&data).with_context(|| format!("Could not parse {lockfile:?}"))?;
+    Ok(lockfile
+        .package
+        .into_iter()
+        .map(|package| {
+            let source = package.source.unwrap_or_default();
+            format!("{} {} {source}", package.name, package.version)
+        })
+        .collect())
+}
+
+// Identify a package by its name, version, and source, which determine its contents.
+fn package_key(package: &Package) -> String {
+    let source = package.source.as_ref().map_or("", |source| &source.repr);
+    format!("{} {} {source}", package.name, package.version)
+}
+
+// The copyrights in the existing license file that `--incremental` reuses, for the packages that
+// are unchanged since it was written.
+#[derive(Default)]
+struct ReusedCopyrights {
+    packages: HashSet<String>,
+    // The copyrights of the components, leaving out those with several records.
+    copyrights: HashMap<String, String>,
+}
+
+impl ReusedCopyrights {
+    fn load(args: &Args, dest: &Path, headers: &Headers) -> Result<Self> {
+        let filename = args
+            .metadata_file
+            .as_deref()
+            .expect("`--incremental` requires `--metadata-file`");
+        if !filename.is_file() {
+            eprintln!("{filename:?} does not exist, scanning all packages.");
+            return Ok(Self::default());
+        }
+        let provenance = Provenance::read(filename)?;
+        if provenance.options_sha256 != options_hash(args)? {
+            eprintln!(
+                "The configuration or options have changed since {filename:?} was written, \
+                 scanning all packages."
+            );
+            return Ok(Self::default());
+        }
+        let mut copyrights = HashMap::new();
+        let mut repeated = HashSet::new();
+        for record in read_records(dest, headers)? {
+            if let Some(copyright) = record.copyright {
+                if copyrights
+                    .insert(record.component.clone(), copyright)
+                    .is_some()
+                {
+                    repeated.insert(record.component);
+                }
+            }
+        }
+        copyrights.retain(|component, _| !repeated.contains(component));
+        Ok(Self {
+            packages: provenance.packages.into_iter().collect(),
+            copyrights,
+        })
     }
-}
 
-// Collect the given records into sets having identical details except for the component names, which
-// are extracted into the hash set value.
-fn collect_record_sets(records: impl Iterator<Item = Record>) -> HashMap<Record, HashSet<String>> {
-    // Translate the packages into records, and deduplicate nearly identical records that differ
-    // only in the component names.
-    let mut intermediate: HashMap<Record, HashSet<String>> = HashMap::default();
-    for mut record in records {
-        let name = take(&mut record.component);
-        intermediate.entry(record).or_default().insert(name);
+    // The copyright to reuse for a package, if it is unchanged.
+    fn get(&self, package: &Package) -> Option<&str> {
+        if !self.packages.contains(&package_key(package)) {
+            return None;
+        }
+        self.copyrights
+            .get(package.name.as_str())
+            .map(String::as_str)
     }
-    intermediate
 }
 
-// This "rehydrates" the record that is missing a component name into potentially multiple records
-// using the set of component names, while attempting to reduce the set down to a single entry.
-fn reduce_names(mut record: Record, names: HashSet<String>) -> Vec<Record> {
-    if names.len() == 1 {
-        record.component = names.into_iter().next().unwrap();
-        vec![record]
-    } else {
-        // If one of the component names matches the repository suffix, use just that one record.
-        if let Some((_, suffix)) = record.origin.rsplit_once('/') {
-            if names.contains(suffix) {
-                record.component = suffix.into();
-                return vec![record];
-            }
-            if let Some(name) = suffix.strip_prefix("rust-") {
-                if names.contains(name) {
-                    record.component = name.into();
-                    return vec![record];
-                }
-            }
-            if let Some(name) = suffix.strip_suffix("-rs") {
-                if names.contains(name) {
-                    record.component = name.into();
-                    return vec![record];
-                }
-            }
-            // More common patterns may be added here as needed to reduce the license file.
+// Read the hash from the comment line at the end of an existing license file, if it has one.
+fn read_hash(filename: &Path) -> Result<Option<String>> {
+    let data = match fs::read_to_string(filename) {
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        result => result.with_context(|| format!("Could not read {filename:?}"))?,
+    };
+    Ok(data
+        .lines()
+        .rev()
+        .find_map(|line| line.trim_end().strip_prefix(HASH_PREFIX))
+        .map(Into::into))
+}
+
+// Dump the records with a header line before each group of records with the same license. The
+// records must already be sorted by license.
+fn output_license_groups(records: Vec<Record>, headers: &Headers) -> Result<()> {
+    let mut records = records.into_iter().peekable();
+    let mut has_headers = true;
+    while let Some(license) = records.peek().map(|record| record.license.clone()) {
+        println!("--- {license} ---");
+        let mut csv = table_writer(io::stdout());
+        if has_headers {
+            write_headers(&mut csv, records.peek().unwrap(), headers, &[])?;
         }
-        // There is no obvious component name to use as the primary, so just include them all.
-        names
-            .into_iter()
-            .map(|component| Record {
-                component,
-                ..record.clone()
-            })
-            .collect()
+        while let Some(record) = records.next_if(|record| record.license == license) {
+            csv.serialize(record)?;
+        }
+        csv.flush()?;
+        has_headers = false;
     }
+    Ok(())
 }
 
-// Dump the resulting CSV table of records.
-fn output_table(records: Vec<Record>, writer: impl Write) -> Result<()> {
-    let mut csv = csv::Writer::from_writer(writer);
-    for record in records {
-        csv.serialize(record)?;
-    }
-    csv.flush().map_err(Into::into)
+// The CSV writer for the records, which writes the header line itself so the columns can be renamed.
+fn table_writer<W: Write>(writer: W) -> csv::Writer<W> {
+    csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer)
+}
+
+// Write the header line for the columns present in the record, followed by any annotation columns.
+fn write_headers(
+    csv: &mut csv::Writer<impl Write>,
+    record: &Record,
+    headers: &Headers,
+    annotations: &[String],
+) -> Result<()> {
+    let fields = record.fields();
+    let names = fields.iter().map(|(name, _)| headers.header(name));
+    csv.write_record(names.chain(annotations.iter().map(String::as_str)))
+        .map_err(Into::into)
 }
 
-// Rewrite package repository and check presence of licenses
-fn rewrite_packages(packages: &mut [Package], overrides: &Overrides) -> Result<()> {
+// Rewrite package repository and check presence of licenses, returning the number of packages
+// that had overrides applied.
+fn rewrite_packages(
+    packages: &mut Vec<Package>,
+    overrides: &Overrides,
+    config: &Config,
+) -> Result<usize> {
+    let overridden = packages
+        .iter()
+        .filter(|package| find_override(overrides, package).is_some())
+        .count();
     let errors = packages.iter_mut().fold(false, |errors, package| {
-        errors | rewrite_package(package, overrides)
+        errors | rewrite_package(package, overrides, config)
     });
     if errors {
         bail!("Could not fix up package details.")
     } else {
-        Ok(())
+        // Any packages still missing a license are to be skipped.
+        packages.retain(|package| package.license.is_some());
+        Ok(overridden)
     }
 }
 
+// Find the override for a package, preferring one for its specific version.
+fn find_override<'a>(overrides: &'a Overrides, package: &Package) -> Option<&'a Override> {
+    override_key(overrides, package).map(|key| &overrides[key])
+}
+
+// Find the key of the override for a package, preferring one for its specific version.
+fn override_key<'a>(overrides: &'a Overrides, package: &Package) -> Option<&'a str> {
+    overrides
+        .get_key_value(&format!("{}-{}", package.name, package.version))
+        .or_else(|| overrides.get_key_value(&package.name))
+        .map(|(key, _)| key.as_str())
+}
+
+// Collect the overrides declared in the `[package.metadata.license-tool.overrides]` table of each
+// workspace member, along with the manifest declaring them.
+fn embedded_overrides(
+    packages: &[Package],
+    members: &[PackageId],
+) -> Result<Vec<(PathBuf, Overrides)>> {
+    packages
+        .iter()
+        .filter(|package| members.contains(&package.id))
+        .filter_map(|package| {
+            let table = package.metadata.get("license-tool")?.get("overrides")?;
+            let manifest = package.manifest_path.clone().into_std_path_buf();
+            Some(
+                Overrides::deserialize(table)
+                    .map(|overrides| (manifest, overrides))
+                    .with_context(|| {
+                        format!(
+                            "Invalid license-tool overrides in {}",
+                            package.manifest_path
+                        )
+                    }),
+            )
+        })
+        .collect()
+}
+
 // Rewrite package details, pulling in overrides, to ensure packages with a source also have a
 // repository set to `Some`.
-fn rewrite_package(package: &mut Package, overrides: &Overrides) -> bool {
+fn rewrite_package(package: &mut Package, overrides: &Overrides, config: &Config) -> bool {
     let name = format!("{}-{}", package.name, package.version);
 
-    if let Some(opts) = overrides
-        .get(&name)
-        .or_else(|| overrides.get(&package.name))
-    {
-        opts.fixup(package);
+    apply_override(package, overrides);
+
+    // Local packages have already been skipped, apart from any workspace members to include.
+    let git = package
+        .source
+        .as_ref()
+        .and_then(|source| source.repr.strip_prefix("git+"));
+    if let Some(repo) = &mut package.repository {
+        *repo = repository_base(repo);
+    } else if let Some(git) = git {
+        package.repository = Some(repository_base(git));
+    } else if let Some(homepage) = package.homepage.clone() {
+        package.repository = Some(homepage);
+    } else if config.fallback_origin_cratesio && is_from_crates_io(package) {
+        warn!(
+            Warning::FallbackOrigin,
+            "Package {name} is missing a repository, using its crates.io page"
+        );
+        package.repository = Some(format!("https://crates.io/crates/{}", package.name));
+    } else if let Some(registry) = alternative_registry(package) {
+        warn!(
+            Warning::FallbackOrigin,
+            "Package {name} is missing a repository, using the URL of its registry: {}",
+            registry_name(registry, &config.registry_names)
+        );
+        package.repository = Some(repository_base(registry));
+    } else {
+        report!("Package {name} is missing a repository");
+        return true;
+    }
+    if package.license.is_none() {
+        match config.missing_license_behavior {
+            MissingLicenseBehavior::Error => {
+                report!("Package {name} is missing a license");
+                return true;
+            }
+            MissingLicenseBehavior::Warn => {
+                warn!(
+                    Warning::MissingLicense,
+                    "Package {name} is missing a license, leaving it empty"
+                );
+                package.license = Some(String::new());
+            }
+            MissingLicenseBehavior::Skip => {
+                warn!(
+                    Warning::MissingLicense,
+                    "Package {name} is missing a license, skipping it"
+                );
+            }
+            MissingLicenseBehavior::Placeholder => {
+                package.license = Some("UNKNOWN".into());
+            }
+        }
     }
+    false
+}
 
-    // Don't rewrite local packages by skipping packages without a source.
-    if let Some(source) = &package.source {
-        // Can't borrow `repo` as mutable after immutable borrow in order to use `.clone_into(repo)`.
-        #[allow(clippy::assigning_clones)]
-        if let Some(repo) = &mut package.repository {
-            *repo = strip_git(repo).to_owned();
-        } else if let Some(git) = source.repr.strip_prefix("git+") {
-            let base = git.find('?').map(|i| &git[..i]).unwrap_or(git);
-            package.repository = Some(strip_git(base).to_owned());
-        } else if let Some(homepage) = package.homepage.clone() {
-            package.repository = Some(homepage);
-        } else {
-            eprintln!("Package {name} is missing a repository");
-            return true;
+// The directories searched for vendored code by `scan_vendored`, by default.
+const DEFAULT_VENDORED_DIRS: [&str; 3] = ["vendor", "third_party", "c_src"];
+
+// Phrases identifying the text of common licenses, where every phrase of an entry must appear. The
+// entries are checked in order, so that the GNU lesser licenses, which mention the general ones,
+// come first.
+const LICENSE_TEXTS: [(&str, &[&str]); 14] = [
+    (
+        "Apache-2.0",
+        &["Apache License", "Version 2.0, January 2004"],
+    ),
+    ("MIT", &["Permission is hereby granted, free of charge"]),
+    (
+        "BSD-3-Clause",
+        &[
+            "Redistribution and use in source and binary forms",
+            "Neither the name",
+        ],
+    ),
+    (
+        "BSD-2-Clause",
+        &["Redistribution and use in source and binary forms"],
+    ),
+    (
+        "ISC",
+        &["Permission to use, copy, modify, and/or distribute this software"],
+    ),
+    (
+        "Zlib",
+        &[
+            "This software is provided 'as-is'",
+            "Altered source versions must be plainly marked",
+        ],
+    ),
+    ("BSL-1.0", &["Boost Software License - Version 1.0"]),
+    ("MPL-2.0", &["Mozilla Public License Version 2.0"]),
+    (
+        "Unlicense",
+        &["This is free and unencumbered software released into the public domain"],
+    ),
+    (
+        "LGPL-2.1-only",
+        &[
+            "GNU LESSER GENERAL PUBLIC LICENSE",
+            "Version 2.1, February 1999",
+        ],
+    ),
+    (
+        "LGPL-3.0-only",
+        &[
+            "GNU LESSER GENERAL PUBLIC LICENSE",
+            "Version 3, 29 June 2007",
+        ],
+    ),
+    (
+        "GPL-2.0-only",
+        &["GNU GENERAL PUBLIC LICENSE", "Version 2, June 1991"],
+    ),
+    (
+        "GPL-3.0-only",
+        &["GNU GENERAL PUBLIC LICENSE", "Version 3, 29 June 2007"],
+    ),
+    ("OpenSSL", &["OpenSSL License", "Original SSLeay License"]),
+];
+
+// Find the vendored code in a package, under the configured directories or their subdirectories
+// holding license files, and make a package for each with the component name
+// `<crate>:<directory>`. Its license is identified from the license files and its copyright is
+// scanned from the vendored directory, while its origin is that of the package. Licenses that are
+// not identified are reported, and left as `UNKNOWN` unless an override sets them.
+fn vendored_packages(
+    package: &Package,
+    overrides: &Overrides,
+    config: &Config,
+) -> Result<Vec<Package>> {
+    if let Some(crates) = &config.scan_vendored_crates {
+        if !crates.contains(&package.name) {
+            return Ok(Vec::new());
+        }
+    }
+    let source_path = package
+        .manifest_path
+        .parent()
+        .map_or_else(PathBuf::new, |path| path.as_std_path().to_path_buf());
+    let mut found = Vec::new();
+    let dirs = config.vendored_dirs.as_ref().map_or_else(
+        || DEFAULT_VENDORED_DIRS.map(String::from).to_vec(),
+        Clone::clone,
+    );
+    for dir in dirs {
+        let base = source_path.join(&dir);
+        if !base.is_dir() {
+            continue;
+        }
+        let files = license_files(&base)?;
+        if !files.is_empty() {
+            found.push((dir, base, files));
+            continue;
         }
+        let mut entries = fs::read_dir(&base)
+            .with_context(|| format!("Could not read {base:?}"))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()
+            .with_context(|| format!("Could not read {base:?}"))?;
+        entries.sort();
+        for path in entries.into_iter().filter(|path| path.is_dir()) {
+            let files = license_files(&path)?;
+            if !files.is_empty() {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                found.push((format!("{dir}/{name}"), path, files));
+            }
+        }
+    }
+
+    let mut vendored = Vec::new();
+    for (dir, path, files) in found {
+        let mut package = package.clone();
+        package.name = format!("{}:{dir}", package.name);
+        package.id.repr = format!("{}#{dir}", package.id.repr);
+        // The copyright scanner looks in the directory of the manifest.
+        package.manifest_path = Utf8PathBuf::from_path_buf(path.join("Cargo.toml"))
+            .map_err(|path| anyhow::anyhow!("{path:?} is not a valid UTF-8 path"))?;
+        package.license_file = None;
+        package.authors = Vec::new();
+        if let Value::Object(map) = &mut package.metadata {
+            for key in ["copyright", OVERRIDE_KEY, INDEX_NAME_KEY] {
+                map.remove(key);
+            }
+        }
+        package.license = identify_licenses(&files)?;
+        apply_override(&mut package, overrides);
         if package.license.is_none() {
-            eprintln!("Package {name} is missing a license");
-            return true;
+            warn!(
+                Warning::MissingLicense,
+                "Could not identify the license of the vendored code in {dir:?}, add an override \
+                 for {:?} to set it",
+                package.name
+            );
+            package.license = Some("UNKNOWN".into());
         }
+        vendored.push(package);
+    }
+    Ok(vendored)
+}
+
+// The license, copying, notice, and copyright files directly in a directory, in order.
+fn license_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Could not read {dir:?}"))? {
+        let path = entry
+            .with_context(|| format!("Could not read {dir:?}"))?
+            .path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if CopyrightClass::of(&name) != CopyrightClass::Readme && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Identify the licenses of vendored code from its license and copying files, combining several with
+// `AND`. Notice and copyright files are only used when they name an SPDX identifier. Returns `None`
+// when a license or copying file is not recognized.
+fn identify_licenses(files: &[PathBuf]) -> Result<Option<String>> {
+    let mut ids: Vec<String> = Vec::new();
+    for path in files {
+        let bytes = fs::read(path).with_context(|| format!("Could not read {path:?}"))?;
+        let text = String::from_utf8_lossy(&bytes);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let required = matches!(
+            CopyrightClass::of(&name),
+            CopyrightClass::License | CopyrightClass::Copying
+        );
+        match identify_license(&text) {
+            Some(id) if !ids.contains(&id) => ids.push(id),
+            Some(_) => (),
+            None if required => return Ok(None),
+            None => (),
+        }
+    }
+    Ok((!ids.is_empty()).then(|| ids.join(" AND ")))
+}
+
+// Identify a license from an `SPDX-License-Identifier` line or else its text.
+fn identify_license(text: &str) -> Option<String> {
+    let spdx = text.lines().find_map(|line| {
+        let (_, id) = line.split_once("SPDX-License-Identifier:")?;
+        Some(id.trim().trim_end_matches("*/").trim())
+    });
+    if let Some(id) = spdx.filter(|id| !id.is_empty()) {
+        return Some(id.into());
+    }
+    // Line breaks and indentation vary between copies of the same license.
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    LICENSE_TEXTS
+        .iter()
+        .find(|(_, phrases)| phrases.iter().all(|phrase| text.contains(phrase)))
+        .map(|(id, _)| (*id).into())
+}
+
+// Apply the override for a package, if it has one, recording what it changed for the override log.
+fn apply_override(package: &mut Package, overrides: &Overrides) {
+    if let Some(key) = override_key(overrides, package) {
+        let opts = &overrides[key];
+        let entry = opts.log_entry(key, package);
+        opts.fixup(package);
+        let entry = serde_json::to_value(entry).expect("Log entries are serializable");
+        set_metadata(package, OVERRIDE_KEY, entry);
+    }
+}
+
+// Clean up a repository URL like `strip_git`, parsing it first where possible so that the query
+// string and fragment are found by the URL syntax rather than by searching the text, and the path
+// keeps its percent-encoding. Whitespace is left for `normalize_origin` to reject, rather than
+// being encoded by the parser.
+fn repository_base(repository: &str) -> String {
+    match Url::parse(repository) {
+        Ok(mut url) if !repository.contains(char::is_whitespace) => {
+            url.set_query(None);
+            url.set_fragment(None);
+            let path = strip_git(url.path()).to_owned();
+            url.set_path(&path);
+            strip_suffix(url.as_str(), "/").into()
+        }
+        _ => strip_git(repository).into(),
     }
-    false
 }
 
+// Clean up a repository URL by removing any query string or fragment and a trailing `.git` or `/`.
 fn strip_git(s: &str) -> &'_ str {
-    strip_suffix(strip_suffix(s, ".git"), "/")
+    let base = s.find(['?', '#']).map(|i| &s[..i]).unwrap_or(s);
+    strip_suffix(strip_suffix(base, ".git"), "/")
 }
 
 fn strip_suffix<'a>(s: &'a str, suffix: &str) -> &'a str {
     s.strip_suffix(suffix).unwrap_or(s)
 }
 
-fn fixup_names(packages: &mut [Package]) -> Result<()> {
+fn fixup_names(packages: &mut [Package], root: &Path) -> Result<()> {
     for package in packages {
-        let path = &package.manifest_path;
-        let text = fs::read_to_string(path)
+        let path = relative_path(package.manifest_path.as_std_path(), root);
+        let text = fs::read_to_string(&package.manifest_path)
             .with_context(|| format!("Could not read manifest in {path:?}"))?;
         let manifest: Manifest = toml::from_str(&text)
             .with_context(|| format!("Could not parse manifest in {path:?}"))?;
-        package.name = manifest.package.name;
+        if manifest.package.name != package.name {
+            // Keep the name known to the registry and `Cargo.lock` for `--include-index-name`.
+            let index_name = Value::String(take(&mut package.name));
+            set_metadata(package, INDEX_NAME_KEY, index_name);
+            package.name = manifest.package.name;
+        }
     }
     Ok(())
 }
 
+// Shorten a path for display by making it relative to the workspace root, when it is within it.
+fn relative_path<'a>(path: &'a Path, root: &Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
 // Look through the source files of every package to find something that looks like a copyright
-// line, and store the result into the package metadata.
-fn lookup_all_copyrights(packages: &mut [Package]) -> Result<()> {
-    for package in packages {
-        let copyright = Value::String(lookup_copyrights(package)?);
-        let key = COPYRIGHT_KEY.to_string();
-        match &mut package.metadata {
-            Value::Null => {
-                package.metadata = Value::Object([(key, copyright)].into_iter().collect())
+// line, using up to the given number of threads, and store the result into the package metadata.
+// Packages with a reused copyright are not scanned. Returns the number of packages where a
+// copyright line was found, and the number with a reused copyright.
+fn lookup_all_copyrights(
+    packages: &mut [Package],
+    scanner: &CopyrightScanner,
+    reused: &ReusedCopyrights,
+    jobs: usize,
+) -> Result<(usize, usize)> {
+    let mut reused_count = 0;
+    for package in packages.iter_mut() {
+        if let Some(copyright) = reused.get(package) {
+            set_metadata(package, COPYRIGHT_KEY, Value::String(copyright.into()));
+            reused_count += 1;
+        }
+    }
+    let pending: Vec<&mut Package> = packages
+        .iter_mut()
+        .filter(|package| package.metadata.get(COPYRIGHT_KEY).is_none())
+        .collect();
+    let results = parallel_map(&pending, jobs, |package| scanner.lookup_copyrights(package));
+    let mut detected = 0;
+    for (package, result) in pending.into_iter().zip(results) {
+        let (copyright, found) = result?;
+        detected += usize::from(found);
+        set_metadata(package, COPYRIGHT_KEY, Value::String(copyright));
+    }
+    Ok((detected, reused_count))
+}
+
+// The settings used when searching a package for its copyright.
+struct CopyrightScanner<'a> {
+    source: CopyrightSource,
+    encoding: CopyrightEncoding,
+    overrides: &'a Overrides,
+    re_copyright: Regex,
+    re_copyright_ignore: Regex,
+    scan_lines: usize,
+    multiline: bool,
+    // The number of consecutive lines joined together when searching for a copyright.
+    max_lines: Option<NonZeroUsize>,
+    max_length: Option<usize>,
+    min_length: usize,
+    // The classes of files scanned, in order.
+    priority: Vec<CopyrightClass>,
+    separator: String,
+    authors_join: AuthorsJoinStrategy,
+    // The directory that paths in messages are shown relative to.
+    root: PathBuf,
+    // The files read while scanning, for `--emit-make-deps`.
+    files_read: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl<'a> CopyrightScanner<'a> {
+    fn new(args: &Args, config: &Config, overrides: &'a Overrides, root: PathBuf) -> Self {
+        // The patterns given as options take precedence over those in the configuration.
+        let mut re_copyright = args
+            .copyright_regex
+            .clone()
+            .or_else(|| config.copyright_regex.as_ref().map(|re| re.0.clone()))
+            .unwrap_or_else(|| RE_COPYRIGHT.clone());
+        if config.multiline {
+            // Match `$` at the end of each line, treating CRLF line endings like LF.
+            re_copyright = Regex::new(&format!("(?mR){re_copyright}"))
+                .expect("Adding flags keeps the pattern valid");
+        }
+        Self {
+            source: config.copyright_source,
+            encoding: config.copyright_encoding,
+            overrides,
+            re_copyright,
+            re_copyright_ignore: args
+                .copyright_ignore_regex
+                .clone()
+                .or_else(|| {
+                    config
+                        .copyright_ignore_regex
+                        .as_ref()
+                        .map(|re| re.0.clone())
+                })
+                .unwrap_or_else(|| RE_COPYRIGHT_IGNORE.clone()),
+            scan_lines: args.copyright_scan_lines,
+            multiline: config.multiline,
+            max_lines: config.max_copyright_lines,
+            max_length: config.max_copyright_length,
+            min_length: config
+                .copyright_min_length
+                .unwrap_or(DEFAULT_MIN_COPYRIGHT_LENGTH),
+            priority: config
+                .copyright_priority
+                .clone()
+                .unwrap_or_else(|| DEFAULT_COPYRIGHT_PRIORITY.into()),
+            separator: config
+                .copyright_separator
+                .clone()
+                .unwrap_or_else(|| DEFAULT_COPYRIGHT_SEPARATOR.into()),
+            authors_join: config.authors_join_strategy,
+            root,
+            files_read: Mutex::default(),
+        }
+    }
+
+    // Find the copyright for the package, along with whether it was detected in the package files.
+    fn lookup_copyrights(&self, package: &Package) -> Result<(String, bool)> {
+        match self.source {
+            CopyrightSource::Scan => (),
+            CopyrightSource::AuthorsFirst => {
+                if let Some(copyright) = self.authors_copyright(package) {
+                    return Ok((copyright, false));
+                }
+            }
+            CopyrightSource::AuthorsOnly => return Ok((self.fallback_copyright(package), false)),
+        }
+        Ok(match self.scan_copyrights(package)? {
+            Some(copyright) => (copyright, true),
+            None => (self.fallback_copyright(package), false),
+        })
+    }
+
+    // Search the package's license and readme files for a copyright line, preferring a copyright
+    // declared in the manifest metadata and then the holders listed in a machine-readable Debian
+    // copyright file.
+    fn scan_copyrights(&self, package: &Package) -> Result<Option<String>> {
+        if let Some(copyright) = manifest_copyright(package) {
+            return Ok(Some(copyright));
+        }
+        // Only the copyrights found in the files are truncated, not those the package declares.
+        let copyright = self.scan_files(package)?;
+        Ok(match self.max_length {
+            Some(max_length) => copyright.map(|copyright| truncate_text(copyright, max_length)),
+            None => copyright,
+        })
+    }
+
+    // Search the Debian copyright file, the license file named in the manifest, and then the files
+    // in the copyright locations for a copyright line.
+    fn scan_files(&self, package: &Package) -> Result<Option<String>> {
+        let mut source_path = PathBuf::from(&package.manifest_path);
+        source_path.pop();
+        let root = symlink_root(&source_path);
+        let encoding = find_override(self.overrides, package)
+            .and_then(|opts| opts.copyright_encoding)
+            .unwrap_or(self.encoding);
+        let debian_path = source_path.join(DEBIAN_COPYRIGHT);
+        if let Some(debian_path) =
+            resolve_symlink(&package.name, &debian_path, root.as_deref(), &self.root)
+        {
+            if debian_path.is_file() {
+                if let Some(copyright) = self.debian_copyright(&debian_path, encoding)? {
+                    return Ok(Some(copyright));
+                }
             }
-            Value::Object(map) => {
-                map.insert(key, copyright);
+        }
+        if let Some(filename) = &package.license_file {
+            let license_path = source_path.join(filename);
+            if let Some(license_path) =
+                resolve_symlink(&package.name, &license_path, root.as_deref(), &self.root)
+            {
+                if let Some(copyright) = self.lookup_copyright(&license_path, encoding, false)? {
+                    return Ok(Some(copyright));
+                }
+            }
+        }
+        // The files are scanned by class, keeping the order of the locations within each class.
+        let locations = self.priority.iter().flat_map(|&class| {
+            COPYRIGHT_LOCATIONS
+                .into_iter()
+                .filter(move |location| CopyrightClass::of(location) == class)
+                .map(move |location| (location, class))
+        });
+        for (location, class) in locations {
+            let path = source_path.join(location);
+            if let Some(path) = resolve_symlink(&package.name, &path, root.as_deref(), &self.root) {
+                if path.is_file() {
+                    let readme = class == CopyrightClass::Readme;
+                    if let Some(copyright) = self.lookup_copyright(&path, encoding, readme)? {
+                        return Ok(Some(copyright));
+                    }
+                }
             }
-            _ => panic!("Package metadata must be an object"),
         }
+        Ok(None)
+    }
+
+    // The copyright used when nothing better is found: the manifest authors, or a generic
+    // attribution.
+    fn fallback_copyright(&self, package: &Package) -> String {
+        self.authors_copyright(package)
+            .unwrap_or_else(|| format!("The {} Authors", package.name))
     }
-    Ok(())
-}
 
-fn lookup_copyrights(package: &mut Package) -> Result<String> {
-    let mut source_path = PathBuf::from(&package.manifest_path);
-    source_path.pop();
-    if let Some(filename) = &package.license_file {
-        let license_path = source_path.join(filename);
-        if let Some(copyright) = lookup_copyright(&license_path)? {
-            return Ok(copyright);
+    // Join the manifest authors, one copyright holder each, as configured.
+    fn authors_copyright(&self, package: &Package) -> Option<String> {
+        let authors = &package.authors;
+        match self.authors_join {
+            _ if authors.is_empty() => None,
+            AuthorsJoinStrategy::All => Some(authors.join(&self.separator)),
+            AuthorsJoinStrategy::First => Some(authors[0].clone()),
+            AuthorsJoinStrategy::Count if authors.len() == 1 => Some(authors[0].clone()),
+            AuthorsJoinStrategy::Count => Some(format!("{} contributors", authors.len())),
         }
     }
-    for location in COPYRIGHT_LOCATIONS {
-        let path = source_path.join(location);
-        if path.is_file() {
-            if let Some(copyright) = lookup_copyright(&path)? {
+
+    // Read the copyright holders from a Debian copyright file, returning `None` if it is not in the
+    // machine-readable format so the other files are scanned instead.
+    fn debian_copyright(&self, path: &Path, encoding: CopyrightEncoding) -> Result<Option<String>> {
+        self.record_read(path);
+        let text = fs::read(path)
+            .and_then(|bytes| encoding.decode(bytes))
+            .with_context(|| format!("Could not read {:?}", relative_path(path, &self.root)))?;
+        Ok(dep5_copyright_holders(&text).map(|holders| holders.join(&self.separator)))
+    }
+
+    // Search a file for a copyright line. In a readme, a copyright in its license section is
+    // preferred over one elsewhere in the file.
+    fn lookup_copyright(
+        &self,
+        path: &Path,
+        encoding: CopyrightEncoding,
+        readme: bool,
+    ) -> Result<Option<String>> {
+        self.record_read(path);
+        let text = read_lines(path, self.scan_lines)
+            .and_then(|bytes| encoding.decode(bytes))
+            .with_context(|| format!("Could not read {:?}", relative_path(path, &self.root)))?;
+        if let Some(section) = license_section(&text).filter(|_| readme) {
+            let copyright = if self.max_lines.is_some() || self.multiline {
+                self.find_copyright(section)
+            } else {
+                // Without multiline matching, the pattern only matches at the end of the text, so
+                // each line of the section is tried instead.
+                section.lines().find_map(|line| self.find_copyright(line))
+            };
+            if copyright.is_some() {
                 return Ok(copyright);
             }
         }
+        Ok(self.find_copyright(&text))
+    }
+
+    fn find_copyright(&self, text: &str) -> Option<String> {
+        if let Some(max_lines) = self.max_lines {
+            return self.find_joined_copyright(text, max_lines.get());
+        }
+        if let Some(found) = self.re_copyright.captures(text) {
+            let copyright = &found[0];
+            if !self.re_copyright_ignore.is_match(copyright) && !self.is_too_short(copyright) {
+                if self.multiline {
+                    // The match may continue onto the following lines, which are joined up.
+                    return Some(copyright.split_whitespace().collect::<Vec<_>>().join(" "));
+                }
+                return Some(copyright.into());
+            }
+        }
+        None
+    }
+
+    // Remember a file that was scanned, whether or not a copyright was found in it.
+    fn record_read(&self, path: &Path) {
+        self.files_read
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path.into());
+    }
+
+    // Search each run of up to the given number of consecutive lines, joined with spaces, so that
+    // copyrights broken across lines are found whole. Shorter runs are tried first, so a copyright
+    // on a single line is not joined with the lines after it.
+    fn find_joined_copyright(&self, text: &str, max_lines: usize) -> Option<String> {
+        let lines: Vec<&str> = text.lines().map(str::trim).collect();
+        for start in 0..lines.len() {
+            for end in start + 1..=lines.len().min(start + max_lines) {
+                let joined = lines[start..end].join(" ");
+                if let Some(found) = self.re_copyright.find(&joined) {
+                    let found = found.as_str();
+                    // A copyright holding only the years may be followed by the holders on the
+                    // next lines, which a longer run joins up.
+                    if !self.re_copyright_ignore.is_match(found) && !self.is_too_short(found) {
+                        return Some(found.into());
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Check if a copyright is too short to name its holders, not counting the leading `copyright`.
+    fn is_too_short(&self, copyright: &str) -> bool {
+        let rest = match copyright.get(..9) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("copyright") => &copyright[9..],
+            _ => copyright,
+        };
+        rest.trim().chars().count() < self.min_length
+    }
+}
+
+// Parse a machine-readable Debian copyright file (DEP-5) into its copyright holders. These are taken
+// from the `Files: *` paragraph, or from all of the `Files` paragraphs when there is none. Returns
+// `None` if the text is not in this format or lists no holders.
+fn dep5_copyright_holders(text: &str) -> Option<Vec<String>> {
+    // Each paragraph is a list of fields, whose values include any continuation lines.
+    let mut paragraphs: Vec<Vec<(&str, String)>> = vec![Vec::new()];
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let paragraph = paragraphs.last_mut().unwrap();
+        if line.trim().is_empty() {
+            if !paragraph.is_empty() {
+                paragraphs.push(Vec::new());
+            }
+        } else if line.starts_with([' ', '\t']) {
+            let (_, value) = paragraph.last_mut()?;
+            value.push('\n');
+            value.push_str(line.trim());
+        } else {
+            let (name, value) = line.split_once(':')?;
+            paragraph.push((name.trim(), value.trim().into()));
+        }
+    }
+    let field = |paragraph: &[(&str, String)], name: &str| {
+        paragraph
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+    // The header paragraph identifies the format.
+    field(&paragraphs[0], "Format")?;
+    let files: Vec<_> = paragraphs[1..]
+        .iter()
+        .filter(|paragraph| field(paragraph, "Files").is_some())
+        .collect();
+    let selected = match files
+        .iter()
+        .find(|paragraph| field(paragraph, "Files").as_deref() == Some("*"))
+    {
+        Some(paragraph) => vec![*paragraph],
+        None => files,
+    };
+    let mut holders = Vec::new();
+    for copyright in selected
+        .iter()
+        .filter_map(|paragraph| field(paragraph, "Copyright"))
+    {
+        for holder in copyright
+            .lines()
+            .filter(|line| !line.is_empty() && *line != ".")
+        {
+            if !holders.iter().any(|existing| existing == holder) {
+                holders.push(holder.to_owned());
+            }
+        }
+    }
+    (!holders.is_empty()).then_some(holders)
+}
+
+// The copyright declared by the package itself in `[package.metadata] copyright = "..."`.
+fn manifest_copyright(package: &Package) -> Option<String> {
+    let copyright = package.metadata.get("copyright")?.as_str()?.trim();
+    (!copyright.is_empty()).then(|| copyright.into())
+}
+
+// Truncate an over-long copyright or description at a word boundary to fit within the maximum number
+// of characters, including the ellipsis that marks the truncation.
+fn truncate_text(text: String, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text;
+    }
+    let end = text
+        .char_indices()
+        .nth(max_length.saturating_sub(1))
+        .map_or(text.len(), |(index, _)| index);
+    let truncated = &text[..end];
+    // Back up to the last word boundary, unless there is none to back up to.
+    let truncated = match truncated.rfind(char::is_whitespace) {
+        Some(index) if !text[end..].starts_with(char::is_whitespace) => &truncated[..index],
+        _ => truncated,
+    };
+    format!("{}…", truncated.trim_end())
+}
+
+// Find the `License`, `Licence`, or `Copyright` section of a readme, from its heading to the next
+// heading of the same or a higher level. Headings either start with `#` or are underlined with `=`
+// or `-`, and lines in fenced code blocks are skipped.
+fn license_section(text: &str) -> Option<&str> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        lines.push((offset, line.trim_end()));
+        offset += line.len();
+    }
+    // The level of the heading at a line, its title, and the number of lines it takes up.
+    let heading = |index: usize| -> Option<(usize, &str, usize)> {
+        let line = lines[index].1;
+        let hashes = line.len() - line.trim_start_matches('#').len();
+        if (1..=6).contains(&hashes) {
+            let title = &line[hashes..];
+            if title.is_empty() || title.starts_with(' ') {
+                return Some((hashes, title.trim().trim_end_matches('#').trim_end(), 1));
+            }
+        }
+        let underline = lines.get(index + 1)?.1.trim();
+        if line.trim().is_empty() || underline.is_empty() {
+            return None;
+        }
+        if underline.chars().all(|c| c == '=') {
+            Some((1, line.trim(), 2))
+        } else if underline.chars().all(|c| c == '-') {
+            Some((2, line.trim(), 2))
+        } else {
+            None
+        }
+    };
+    let mut in_fence = false;
+    let mut section: Option<(usize, usize)> = None;
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index].1.trim_start();
+        if line.starts_with("```") || line.starts_with("~~~") {
+            in_fence = !in_fence;
+        } else if !in_fence {
+            if let Some((level, title, length)) = heading(index) {
+                match section {
+                    Some((start, section_level)) if level <= section_level => {
+                        return Some(&text[start..lines[index].0]);
+                    }
+                    None => {
+                        let title = title.to_lowercase();
+                        if title.starts_with("licen") || title.starts_with("copyright") {
+                            let start = lines.get(index + length).map_or(text.len(), |line| line.0);
+                            section = Some((start, level));
+                        }
+                    }
+                    Some(_) => (),
+                }
+                index += length;
+                continue;
+            }
+        }
+        index += 1;
+    }
+    section.map(|(start, _)| &text[start..])
+}
+
+// Read up to the given number of lines from the start of a file, so large files are not read in
+// full. The line endings are kept, and the bytes are left to be decoded by the caller.
+fn read_lines(path: &Path, lines: usize) -> io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut text = Vec::new();
+    for _ in 0..lines {
+        if reader.read_until(b'\n', &mut text)? == 0 {
+            break;
+        }
+    }
+    Ok(text)
+}
+
+// Store a value computed for the package into its metadata, to be picked up in `package_to_record`.
+fn set_metadata(package: &mut Package, key: &str, value: Value) {
+    match &mut package.metadata {
+        Value::Null => {
+            package.metadata = Value::Object([(key.into(), value)].into_iter().collect())
+        }
+        Value::Object(map) => {
+            map.insert(key.into(), value);
+        }
+        _ => panic!("Package metadata must be an object"),
+    }
+}
+
+// The directory that symlinked license files may point into, which is the package directory
+// itself, so that links into sibling crates in a registry are not followed.
+fn symlink_root(source_path: &Path) -> Option<PathBuf> {
+    fs::canonicalize(source_path).ok()
+}
+
+// Resolve the path if it is a symlink, returning `None` when the link is broken or points outside
+// of the allowed root. Paths that are not symlinks are returned unchanged.
+fn resolve_symlink(
+    name: &str,
+    path: &Path,
+    root: Option<&Path>,
+    workspace_root: &Path,
+) -> Option<PathBuf> {
+    let display = relative_path(path, workspace_root);
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => match fs::canonicalize(path) {
+            Ok(target) if root.is_some_and(|root| target.starts_with(root)) => Some(target),
+            Ok(_) => {
+                warn!(
+                    Warning::Symlink,
+                    "Package {name} links {display:?} outside of its source, skipping"
+                );
+                None
+            }
+            Err(_) => {
+                warn!(
+                    Warning::Symlink,
+                    "Package {name} has a broken link at {display:?}, skipping"
+                );
+                None
+            }
+        },
+        _ => Some(path.to_path_buf()),
     }
-    Ok(if package.authors.is_empty() {
-        format!("The {} Authors", package.name)
-    } else {
-        package.authors.join(", ")
-    })
 }
 
-fn lookup_copyright(path: &Path) -> Result<Option<String>> {
-    let text = fs::read_to_string(path).with_context(|| format!("Could not read {path:?}"))?;
-    if let Some(found) = RE_COPYRIGHT.captures(&text) {
-        let copyright = &found[0];
-        if !RE_COPYRIGHT_IGNORE.is_match(copyright) {
-            return Ok(Some(copyright.into()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Create an empty directory for the fixture files of a test, removing any left by an earlier
+    // run.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "dd-rust-license-tool-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Parse the options for a test, with the subcommand given last.
+    fn args(args: &[&str]) -> Args {
+        Args::parse_from(["dd-rust-license-tool"].iter().chain(args))
+    }
+
+    // A package with its manifest in the given directory, with the given fields added to or
+    // replacing those of a minimal package.
+    fn package(dir: &Path, name: &str, fields: Value) -> Package {
+        let mut package = serde_json::json!({
+            "name": name,
+            "version": "1.0.0",
+            "id": format!("registry+https://github.com/rust-lang/crates.io-index#{name}@1.0.0"),
+            "source": "registry+https://github.com/rust-lang/crates.io-index",
+            "dependencies": [],
+            "targets": [],
+            "features": {},
+            "manifest_path": dir.join("Cargo.toml"),
+        });
+        for (key, value) in fields.as_object().unwrap() {
+            package[key] = value.clone();
+        }
+        serde_json::from_value(package).unwrap()
+    }
+
+    fn scanner<'a>(config: &Config, overrides: &'a Overrides, root: &Path) -> CopyrightScanner<'a> {
+        CopyrightScanner::new(&args(&["check"]), config, overrides, root.into())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_stay_within_the_package() {
+        use std::os::unix::fs::symlink;
+
+        let dir = fixture_dir("symlinks");
+        let package = dir.join("pkg-1.0.0");
+        let sibling = dir.join("other-1.0.0");
+        fs::create_dir_all(&package).unwrap();
+        fs::create_dir_all(&sibling).unwrap();
+        fs::write(
+            package.join("LICENSE.txt"),
+            "Copyright 2024 The Package Authors",
+        )
+        .unwrap();
+        fs::write(sibling.join("LICENSE"), "Copyright 2024 Somebody Else").unwrap();
+        symlink("LICENSE.txt", package.join("LICENSE")).unwrap();
+        symlink("../other-1.0.0/LICENSE", package.join("COPYING")).unwrap();
+        symlink("missing", package.join("NOTICE")).unwrap();
+
+        let root = symlink_root(&package);
+        let resolve = |file| resolve_symlink("pkg", &package.join(file), root.as_deref(), &dir);
+        let target = fs::canonicalize(package.join("LICENSE.txt")).unwrap();
+        assert_eq!(resolve("LICENSE"), Some(target));
+        // A link into a sibling crate, as in the registry source directory, is not followed.
+        assert_eq!(resolve("COPYING"), None);
+        // A broken link is skipped.
+        assert_eq!(resolve("NOTICE"), None);
+        assert_eq!(resolve("LICENSE.txt"), Some(package.join("LICENSE.txt")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn readme_copyright_comes_from_the_license_section() {
+        let dir = fixture_dir("readme");
+        let readme = dir.join("README.md");
+        fs::write(
+            &readme,
+            "# widget\n\
+             \n\
+             Copyright 2020 The Upstream Library, which is vendored.\n\
+             \n\
+             ```\n\
+             # License\n\
+             Copyright 2021 Inside A Code Block\n\
+             ```\n\
+             \n\
+             ## License\n\
+             \n\
+             Copyright 2024 The Widget Authors\n\
+             \n\
+             ## Contributing\n\
+             \n\
+             Copyright 2022 Somebody Else\n",
+        )
+        .unwrap();
+        let overrides = Overrides::default();
+        let encoding = CopyrightEncoding::default();
+        for multiline in [false, true] {
+            let config = Config {
+                multiline,
+                ..Config::default()
+            };
+            let scanner = scanner(&config, &overrides, &dir);
+            assert_eq!(
+                scanner.lookup_copyright(&readme, encoding, true).unwrap(),
+                Some("Copyright 2024 The Widget Authors".into())
+            );
+        }
+        // Outside a readme, the section is not looked for and the first match is taken.
+        let config = Config {
+            multiline: true,
+            ..Config::default()
+        };
+        let scanner = scanner(&config, &overrides, &dir);
+        assert_eq!(
+            scanner.lookup_copyright(&readme, encoding, false).unwrap(),
+            Some("Copyright 2020 The Upstream Library, which is vendored.".into())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn record(component: &str, origin: &str, license: &str) -> Record {
+        Record {
+            component: component.into(),
+            origin: origin.into(),
+            license: license.into(),
+            copyright: None,
+            relation: None,
+            group: None,
+            dependents: None,
+            description: None,
+            download: None,
+            index_name: None,
+            notes: None,
+        }
+    }
+
+    #[test]
+    fn collisions_need_a_different_origin_or_license() {
+        let records = [
+            record("foo", "https://github.com/a/foo", "MIT"),
+            record("foo", "https://github.com/b/foo", "MIT"),
+            record("bar", "https://github.com/a/bar", "MIT"),
+            record("bar", "https://github.com/a/bar", "MIT"),
+            record("baz", "https://github.com/a/baz", "MIT"),
+            record("baz", "https://github.com/a/baz", "Apache-2.0"),
+            record("qux", "https://github.com/a/qux", "MIT"),
+        ];
+        let collisions = collisions(&records);
+        assert_eq!(collisions.keys().collect::<Vec<_>>(), [&"baz", &"foo"]);
+        let origins: Vec<&str> = collisions["foo"]
+            .iter()
+            .map(|record| record.origin.as_str())
+            .collect();
+        assert_eq!(
+            origins,
+            ["https://github.com/a/foo", "https://github.com/b/foo"]
+        );
+    }
+
+    #[test]
+    fn only_scanned_copyrights_are_truncated() {
+        let dir = fixture_dir("truncate");
+        let long = "Copyright 2024 The Widget Authors and many other contributors";
+        fs::write(dir.join("LICENSE"), long).unwrap();
+        let config = Config {
+            max_copyright_length: Some(40),
+            ..Config::default()
+        };
+        let overrides = Overrides::default();
+        let scanner = scanner(&config, &overrides, &dir);
+        let scanned = package(&dir, "widget", serde_json::json!({}));
+        assert_eq!(
+            scanner.lookup_copyrights(&scanned).unwrap(),
+            ("Copyright 2024 The Widget Authors and…".into(), true)
+        );
+        let declared = package(
+            &dir,
+            "widget",
+            serde_json::json!({"metadata": {"copyright": long}}),
+        );
+        assert_eq!(
+            scanner.lookup_copyrights(&declared).unwrap(),
+            (long.into(), true)
+        );
+        fs::remove_file(dir.join("LICENSE")).unwrap();
+        let authors = ["Somebody With A Rather Long Name <somebody@example.com>"];
+        let fallback = package(&dir, "widget", serde_json::json!({"authors": authors}));
+        assert_eq!(
+            scanner.lookup_copyrights(&fallback).unwrap(),
+            (authors[0].into(), false)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn aliases_are_reversed_by_whole_name() {
+        let aliases = BTreeMap::from([
+            ("MIT".to_owned(), "MIT".to_owned()),
+            ("Apache-2.0".to_owned(), "Apache License 2.0".to_owned()),
+            ("GPL-3.0-only".to_owned(), "GPL".to_owned()),
+        ]);
+        assert_eq!(
+            unalias_license("(Apache License 2.0 OR MIT-0) AND GPL-3.0+", &aliases),
+            "(Apache-2.0 OR MIT-0) AND GPL-3.0+"
+        );
+        assert_eq!(
+            unalias_license("GPL OR MIT", &aliases),
+            "GPL-3.0-only OR MIT"
+        );
+
+        let mut config = Config {
+            aliases,
+            ..Config::default()
+        };
+        config.aliases.insert("MIT".into(), "M".into());
+        let records = [record("zero", "https://github.com/a/zero", "MIT-0")];
+        let (categories, unknown) = license_categories(&records, &config);
+        assert_eq!(
+            categories.keys().collect::<Vec<_>>(),
+            [&LicenseCategory::Permissive]
+        );
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn copyright_files_are_scanned_by_class() {
+        let dir = fixture_dir("priority");
+        for (file, holder) in [
+            ("LICENSE-MIT", "MIT Holder"),
+            ("LICENSE-APACHE", "Apache Holder"),
+            ("NOTICE", "Notice Holder"),
+            ("COPYRIGHT", "Copyright Holder"),
+            ("README.md", "Readme Holder"),
+        ] {
+            fs::write(dir.join(file), format!("Copyright 2024 {holder}")).unwrap();
+        }
+        let widget = package(&dir, "widget", serde_json::json!({}));
+        let overrides = Overrides::default();
+        let lookup = |priority: Option<Vec<CopyrightClass>>| {
+            let config = Config {
+                copyright_priority: priority,
+                ..Config::default()
+            };
+            scanner(&config, &overrides, &dir)
+                .lookup_copyrights(&widget)
+                .unwrap()
+        };
+        // Within a class, the files are scanned in the order of the known locations.
+        assert_eq!(lookup(None), ("Copyright 2024 Apache Holder".into(), true));
+        assert_eq!(
+            lookup(Some(vec![CopyrightClass::Readme, CopyrightClass::Notice])),
+            ("Copyright 2024 Readme Holder".into(), true)
+        );
+        assert_eq!(
+            lookup(Some(vec![
+                CopyrightClass::Copying,
+                CopyrightClass::Copyright
+            ])),
+            ("Copyright 2024 Copyright Holder".into(), true)
+        );
+        // A class left out is not scanned at all.
+        assert_eq!(
+            lookup(Some(vec![CopyrightClass::Copying])),
+            ("The widget Authors".into(), false)
+        );
+        fs::remove_file(dir.join("LICENSE-APACHE")).unwrap();
+        assert_eq!(lookup(None), ("Copyright 2024 MIT Holder".into(), true));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn name_reduction_patterns_replace_the_builtin_ones() {
+        let reduce = |origin: &str, names: &[&str], patterns: &[NamePattern]| {
+            let names = names.iter().map(|&name| name.into()).collect();
+            reduce_names(record("", origin, "MIT"), names, patterns)
+                .into_iter()
+                .map(|record| record.component)
+                .collect::<Vec<_>>()
+        };
+        let builtin = &DEFAULT_NAME_REDUCTION_PATTERNS;
+        let origin = "https://github.com/a/rust-foo";
+        assert_eq!(reduce(origin, &["foo", "foo-core"], builtin), ["foo"]);
+        // A repository named exactly like a component takes precedence over the patterns.
+        assert_eq!(reduce(origin, &["foo", "rust-foo"], builtin), ["rust-foo"]);
+
+        let config: Config = toml::from_str(r#"name_reduction_patterns = ["{}-rs"]"#).unwrap();
+        let configured = config.name_reduction_patterns.unwrap();
+        assert_eq!(
+            reduce(origin, &["foo", "foo-core"], &configured),
+            ["foo", "foo-core"]
+        );
+
+        // The first configured pattern that matches a component wins.
+        let origin = "https://github.com/a/foo-core-rs";
+        let config: Config =
+            toml::from_str(r#"name_reduction_patterns = ["{}-core-rs", "{}-rs"]"#).unwrap();
+        let configured = config.name_reduction_patterns.unwrap();
+        assert_eq!(reduce(origin, &["foo", "foo-core"], &configured), ["foo"]);
+        let config: Config =
+            toml::from_str(r#"name_reduction_patterns = ["{}-rs", "{}-core-rs"]"#).unwrap();
+        let configured = config.name_reduction_patterns.unwrap();
+        assert_eq!(
+            reduce(origin, &["foo", "foo-core"], &configured),
+            ["foo-core"]
+        );
+    }
+
+    #[test]
+    fn name_reduction_patterns_need_one_placeholder() {
+        for pattern in ["rust-", "{}-{}"] {
+            let error =
+                toml::from_str::<Config>(&format!("name_reduction_patterns = [{pattern:?}]"))
+                    .err()
+                    .unwrap();
+            let expected =
+                format!("name reduction pattern {pattern:?} must contain `{{}}` exactly once");
+            assert!(error.to_string().contains(&expected), "{error}");
+        }
+    }
+
+    #[test]
+    fn debian_copyright_files() {
+        let dir = fixture_dir("debian");
+        fs::create_dir(dir.join("debian")).unwrap();
+        fs::write(dir.join("LICENSE"), "Copyright 2024 The License File").unwrap();
+        let widget = package(&dir, "widget", serde_json::json!({}));
+        let (config, overrides) = (Config::default(), Overrides::default());
+        let lookup = |debian: &str| {
+            fs::write(dir.join(DEBIAN_COPYRIGHT), debian).unwrap();
+            scanner(&config, &overrides, &dir)
+                .lookup_copyrights(&widget)
+                .unwrap()
+                .0
+        };
+        let header = "Format: https://www.debian.org/doc/packaging-manuals/copyright-format/1.0/\n\
+                      Upstream-Name: widget\n\n";
+        // Only the `Files: *` paragraph is used when there is one.
+        let debian = format!(
+            "{header}\
+             Files: src/vendored/*\n\
+             Copyright: 2019 Vendored Author\n\
+             License: BSD-3-Clause\n\n\
+             Files: *\n\
+             Copyright: 2020-2024 First Holder\n\
+             \x20          2022 Second Holder\n\
+             License: MIT\n"
+        );
+        assert_eq!(
+            lookup(&debian),
+            "2020-2024 First Holder, 2022 Second Holder"
+        );
+        // Otherwise the holders of all the paragraphs are listed once each.
+        let debian = format!(
+            "{header}\
+             Files: src/*\n\
+             Copyright: 2020 First Holder\n\
+             License: MIT\n\n\
+             Files: tests/*\n\
+             Copyright: 2021 Second Holder\n\
+             \x20          2020 First Holder\n\
+             License: MIT\n"
+        );
+        assert_eq!(lookup(&debian), "2020 First Holder, 2021 Second Holder");
+        // A file not in the machine-readable format falls back to scanning the other files.
+        let debian = "This package was debianized by Somebody.\n\n\
+                      Copyright 2018 Not Machine Readable\n";
+        assert_eq!(lookup(debian), "Copyright 2024 The License File");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bash_completions_list_the_subcommands() {
+        Args::command().debug_assert();
+        let mut script = Vec::new();
+        write_completions(Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        for subcommand in ["check", "write"] {
+            let case = format!("dd__rust__license__tool,{subcommand})");
+            assert!(script.contains(&case), "{subcommand} is missing");
+        }
+    }
+
+    #[test]
+    fn check_reads_the_given_output_path() {
+        let dir = fixture_dir("check-output");
+        let dest = dir.join("licenses").join("third-party.csv");
+        let (args, headers) = (args(&["check"]), Headers::default());
+        let records = [Record {
+            copyright: Some("Copyright 2024 The Widget Authors".into()),
+            ..record("widget", "https://github.com/a/widget", "MIT")
+        }];
+        // A missing file has no records, so every generated record is missing.
+        let error = Commands::check(&records, &dest, &headers, &args, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Current {dest:?} is not up to date.")
+        );
+
+        fs::create_dir(dest.parent().unwrap()).unwrap();
+        let file = File::create(&dest).unwrap();
+        output_table(
+            records.to_vec(),
+            &headers,
+            &Annotations::default(),
+            false,
+            file,
+        )
+        .unwrap();
+        Commands::check(&records, &dest, &headers, &args, None).unwrap();
+        let changed = [Record {
+            license: "Apache-2.0".into(),
+            ..records[0].clone()
+        }];
+        let error = Commands::check(&changed, &dest, &headers, &args, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Current {dest:?} is not up to date.")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_are_written_identically_on_every_run() {
+        let dir = fixture_dir("deterministic");
+        let packages = || {
+            [
+                ("toolkit-macros", "app"),
+                ("toolkit", "app"),
+                ("toolkit-core", "app, toolkit"),
+                ("widget", "app"),
+                ("toolkit-util", "app"),
+            ]
+            .map(|(name, dependents)| {
+                let repository = if name == "widget" {
+                    "https://github.com/a/widget"
+                } else {
+                    "https://github.com/a/kit"
+                };
+                package(
+                    &dir,
+                    name,
+                    serde_json::json!({
+                        "license": "MIT OR Apache-2.0",
+                        "repository": repository,
+                        "description": "A  toolkit\nfor widgets.",
+                        "metadata": {
+                            COPYRIGHT_KEY: "Copyright 2024 The Kit Authors",
+                            RELATION_KEY: "transitive",
+                            DEPENDENTS_KEY: dependents,
+                        },
+                    }),
+                )
+            })
+            .to_vec()
+        };
+        let config = Config::default();
+        for no_sort in [false, true] {
+            let mut options = vec![
+                "--include-relation",
+                "--include-groups",
+                "--annotate-dependents",
+                "--include-download",
+                "--include-index-name",
+                "--include-notes",
+                "--include-description",
+            ];
+            if no_sort {
+                options.push("--no-sort");
+            }
+            options.push("write");
+            let args = args(&options);
+            let output = || {
+                let records = build_records(packages(), &args, &config).unwrap();
+                let mut output = Vec::new();
+                output_table(
+                    records,
+                    &config.headers,
+                    &Annotations::default(),
+                    true,
+                    &mut output,
+                )
+                .unwrap();
+                output
+            };
+            let first = output();
+            assert_eq!(first, output());
+            // None of the names matches the shared repository, so each keeps its own row.
+            let text = String::from_utf8(first).unwrap();
+            assert_eq!(
+                text.matches("https://github.com/a/kit").count(),
+                4,
+                "{text}"
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn origins_differing_only_in_spelling_are_merged() {
+        for origin in [
+            "https://github.com/org/repo",
+            "https://github.com/org/repo/",
+            "https://github.com/org/repo.git",
+            "https://github.com/org/repo.git/",
+            "http://github.com/org/repo",
+            "https://GitHub.com/org/repo",
+            "github.com/org/repo",
+            "https://github.com/org/repo?tab=readme#license",
+        ] {
+            assert_eq!(
+                normalize_origin(origin).unwrap(),
+                "https://github.com/org/repo",
+                "{origin}"
+            );
         }
+        // Only the host is case-insensitive.
+        assert_eq!(
+            normalize_origin("https://github.com/Org/Repo").unwrap(),
+            "https://github.com/Org/Repo"
+        );
+
+        let dir = fixture_dir("origins");
+        let packages = [
+            ("repo", "https://github.com/org/repo"),
+            ("repo-derive", "http://GitHub.com/org/repo.git/"),
+        ]
+        .map(|(name, repository)| {
+            package(
+                &dir,
+                name,
+                serde_json::json!({
+                    "license": "MIT",
+                    "repository": repository,
+                    "metadata": {COPYRIGHT_KEY: "Copyright 2024 The Repo Authors"},
+                }),
+            )
+        });
+        let records =
+            build_records(packages.to_vec(), &args(&["write"]), &Config::default()).unwrap();
+        let records: Vec<(&str, &str)> = records
+            .iter()
+            .map(|record| (record.component.as_str(), record.origin.as_str()))
+            .collect();
+        assert_eq!(records, [("repo", "https://github.com/org/repo")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn optional_dependencies_follow_the_resolved_tree() {
+        let dir = Path::new("/work/app");
+        let root = "path+file:///work/app#0.1.0";
+        let app = package(dir, "app", serde_json::json!({"id": root, "source": null}));
+        let [serde, hex] = ["serde", "hex"].map(|name| package(dir, name, serde_json::json!({})));
+        // The manifest also declares `hex` as an optional dependency, but no enabled feature
+        // activates it, so `cargo metadata` resolves no edge to it.
+        let resolve: Resolve = serde_json::from_value(serde_json::json!({
+            "root": root,
+            "nodes": [
+                {
+                    "id": root,
+                    "dependencies": [&serde.id],
+                    "deps": [{
+                        "name": "serde",
+                        "pkg": &serde.id,
+                        "dep_kinds": [{"kind": null, "target": null}],
+                    }],
+                    "features": [],
+                },
+                {"id": &serde.id, "dependencies": [], "deps": [], "features": []},
+                {"id": &hex.id, "dependencies": [], "deps": [], "features": []},
+            ],
+        }))
+        .unwrap();
+        let members = [app.id.clone()];
+        let package_ids = filter_deps(resolve, &members, None);
+        let names: Vec<String> = lookup_deps(package_ids, vec![app, serde, hex], &[])
+            .into_iter()
+            .map(|package| package.name)
+            .collect();
+        assert_eq!(names, ["serde"]);
+    }
+
+    #[test]
+    fn check_hashes_the_records_as_written() {
+        let dir = fixture_dir("check-hash");
+        let dest = dir.join(DEST_FILENAME);
+        let headers = Headers::default();
+        let records = [Record {
+            copyright: Some("Copyright 2024 The Widget Authors".into()),
+            ..record("widget", "https://github.com/a/widget", "MIT")
+        }];
+        let written = [Record {
+            notes: Some("Reviewed".into()),
+            ..records[0].clone()
+        }];
+        let file = File::create(&dest).unwrap();
+        output_table(
+            written.to_vec(),
+            &headers,
+            &Annotations::default(),
+            true,
+            file,
+        )
+        .unwrap();
+        // The notes are not compared without `--include-notes`, but they are still hashed.
+        let without_notes = args(&["--include-hash", "check"]);
+        Commands::check(&records, &dest, &headers, &without_notes, None).unwrap();
+        let with_notes = args(&["--include-hash", "--include-notes", "check"]);
+        Commands::check(&written, &dest, &headers, &with_notes, None).unwrap();
+
+        let text = fs::read_to_string(&dest).unwrap();
+        fs::write(&dest, text.replace("Reviewed", "Tampered")).unwrap();
+        let error = Commands::check(&records, &dest, &headers, &without_notes, None).unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{error}");
+        fs::remove_dir_all(&dir).unwrap();
     }
-    Ok(None)
 }
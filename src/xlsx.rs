@@ -0,0 +1,360 @@
+// A minimal writer for Excel workbooks, holding the SpreadsheetML parts in a ZIP archive with the
+// files stored uncompressed. Each sheet has its first row frozen as the header and its columns
+// sized to their contents.
+
+use std::mem::take;
+
+use anyhow::{Context, Result};
+
+const CONTENT_TYPES_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/><Override PartName="/xl/styles.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.styles+xml"/>"#;
+
+const RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#;
+
+const XML_DECLARATION: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+"#;
+
+const MAIN_NAMESPACES: &str = r#"xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships""#;
+
+const RELATIONSHIPS_NAMESPACE: &str =
+    r#"xmlns="http://schemas.openxmlformats.org/package/2006/relationships""#;
+
+const RELATIONSHIP_TYPES: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+// The cell styles are, in order: the default, bold headers, hyperlinks, and text that Excel keeps
+// as text when the cell is edited.
+const STYLES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<styleSheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><fonts count="3"><font><sz val="11"/><name val="Calibri"/></font><font><b/><sz val="11"/><name val="Calibri"/></font><font><u/><sz val="11"/><color rgb="FF0563C1"/><name val="Calibri"/></font></fonts><fills count="2"><fill><patternFill patternType="none"/></fill><fill><patternFill patternType="gray125"/></fill></fills><borders count="1"><border><left/><right/><top/><bottom/><diagonal/></border></borders><cellStyleXfs count="1"><xf numFmtId="0" fontId="0" fillId="0" borderId="0"/></cellStyleXfs><cellXfs count="4"><xf numFmtId="0" fontId="0" fillId="0" borderId="0" xfId="0"/><xf numFmtId="0" fontId="1" fillId="0" borderId="0" xfId="0" applyFont="1"/><xf numFmtId="0" fontId="2" fillId="0" borderId="0" xfId="0" applyFont="1"/><xf numFmtId="49" fontId="0" fillId="0" borderId="0" xfId="0" applyNumberFormat="1" quotePrefix="1"/></cellXfs></styleSheet>"#;
+
+// The widest a column is sized to fit its contents, in characters.
+const MAX_COLUMN_WIDTH: usize = 80;
+
+pub enum Cell {
+    Header(String),
+    Text(String),
+    Link(String),
+    Number(usize),
+}
+
+impl Cell {
+    fn width(&self) -> usize {
+        match self {
+            Self::Header(text) | Self::Text(text) | Self::Link(text) => text
+                .lines()
+                .map(|line| line.chars().count())
+                .max()
+                .unwrap_or(0),
+            Self::Number(number) => number.to_string().len(),
+        }
+    }
+}
+
+// A worksheet whose first row is frozen as the header.
+#[derive(Default)]
+pub struct Sheet {
+    rows: Vec<Vec<Cell>>,
+    widths: Vec<usize>,
+}
+
+impl Sheet {
+    pub fn add_row(&mut self, cells: Vec<Cell>) {
+        for (column, cell) in cells.iter().enumerate() {
+            if column == self.widths.len() {
+                self.widths.push(0);
+            }
+            self.widths[column] = self.widths[column].max(cell.width());
+        }
+        self.rows.push(cells);
+    }
+
+    // Produce the worksheet XML and its relationships, which hold the targets of the hyperlinks
+    // when there are any.
+    fn to_xml(&self) -> (String, Option<String>) {
+        let mut xml = format!(
+            "{XML_DECLARATION}<worksheet {MAIN_NAMESPACES}><sheetViews><sheetView \
+             workbookViewId=\"0\"><pane ySplit=\"1\" topLeftCell=\"A2\" activePane=\"bottomLeft\" \
+             state=\"frozen\"/></sheetView></sheetViews><cols>"
+        );
+        for (column, width) in self.widths.iter().enumerate() {
+            let width = (width + 2).min(MAX_COLUMN_WIDTH);
+            let column = column + 1;
+            xml += &format!(
+                "<col min=\"{column}\" max=\"{column}\" width=\"{width}\" customWidth=\"1\"/>"
+            );
+        }
+        xml += "</cols><sheetData>";
+        let mut links = Vec::new();
+        for (row, cells) in self.rows.iter().enumerate() {
+            let row = row + 1;
+            xml += &format!("<row r=\"{row}\">");
+            for (column, cell) in cells.iter().enumerate() {
+                let reference = format!("{}{row}", column_name(column));
+                let (style, text) = match cell {
+                    Cell::Number(number) => {
+                        xml += &format!("<c r=\"{reference}\"><v>{number}</v></c>");
+                        continue;
+                    }
+                    Cell::Header(text) => (1, text),
+                    Cell::Link(text) => {
+                        links.push((reference.clone(), text));
+                        (2, text)
+                    }
+                    // Excel would otherwise read text starting with these as a formula once the
+                    // cell is edited.
+                    Cell::Text(text) if text.starts_with(['=', '+', '-', '@']) => (3, text),
+                    Cell::Text(text) => (0, text),
+                };
+                xml += &format!(
+                    "<c r=\"{reference}\" s=\"{style}\" t=\"inlineStr\"><is>\
+                     <t xml:space=\"preserve\">{}</t></is></c>",
+                    xml_escape(text)
+                );
+            }
+            xml += "</row>";
+        }
+        xml += "</sheetData>";
+        if links.is_empty() {
+            xml += "</worksheet>";
+            return (xml, None);
+        }
+        let mut rels = format!("{XML_DECLARATION}<Relationships {RELATIONSHIPS_NAMESPACE}>");
+        xml += "<hyperlinks>";
+        for (index, (reference, target)) in links.into_iter().enumerate() {
+            let id = index + 1;
+            xml += &format!("<hyperlink ref=\"{reference}\" r:id=\"rId{id}\"/>");
+            rels += &format!(
+                "<Relationship Id=\"rId{id}\" Type=\"{RELATIONSHIP_TYPES}/hyperlink\" \
+                 Target=\"{}\" TargetMode=\"External\"/>",
+                xml_escape(target)
+            );
+        }
+        xml += "</hyperlinks></worksheet>";
+        rels += "</Relationships>";
+        (xml, Some(rels))
+    }
+}
+
+// Build a workbook from the named sheets, in order.
+pub fn workbook(sheets: Vec<(&str, Sheet)>) -> Result<Vec<u8>> {
+    let mut content_types = CONTENT_TYPES_HEADER.to_owned();
+    let mut workbook = format!("{XML_DECLARATION}<workbook {MAIN_NAMESPACES}><sheets>");
+    let mut workbook_rels = format!("{XML_DECLARATION}<Relationships {RELATIONSHIPS_NAMESPACE}>");
+    for (index, (name, _)) in sheets.iter().enumerate() {
+        let id = index + 1;
+        content_types += &format!(
+            "<Override PartName=\"/xl/worksheets/sheet{id}.xml\" ContentType=\"application/\
+             vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml\"/>"
+        );
+        workbook += &format!(
+            "<sheet name=\"{}\" sheetId=\"{id}\" r:id=\"rId{id}\"/>",
+            xml_escape(name)
+        );
+        workbook_rels += &format!(
+            "<Relationship Id=\"rId{id}\" Type=\"{RELATIONSHIP_TYPES}/worksheet\" \
+             Target=\"worksheets/sheet{id}.xml\"/>"
+        );
+    }
+    // The styles follow the sheets, so their relationship has the next ID.
+    workbook_rels += &format!(
+        "<Relationship Id=\"rId{}\" Type=\"{RELATIONSHIP_TYPES}/styles\" Target=\"styles.xml\"/>\
+         </Relationships>",
+        sheets.len() + 1
+    );
+    content_types += "</Types>";
+    workbook += "</sheets></workbook>";
+
+    let mut zip = ZipWriter::default();
+    zip.add("[Content_Types].xml", &content_types)?;
+    zip.add("_rels/.rels", RELS)?;
+    zip.add("xl/workbook.xml", &workbook)?;
+    zip.add("xl/_rels/workbook.xml.rels", &workbook_rels)?;
+    zip.add("xl/styles.xml", STYLES)?;
+    for (index, (_, sheet)) in sheets.into_iter().enumerate() {
+        let id = index + 1;
+        let (xml, rels) = sheet.to_xml();
+        zip.add(&format!("xl/worksheets/sheet{id}.xml"), &xml)?;
+        if let Some(rels) = rels {
+            zip.add(&format!("xl/worksheets/_rels/sheet{id}.xml.rels"), &rels)?;
+        }
+    }
+    zip.finish()
+}
+
+// The letters naming a spreadsheet column, counting from 0 for column A.
+fn column_name(mut column: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (column % 26) as u8);
+        if column < 26 {
+            break;
+        }
+        column = column / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).expect("Column names are ASCII")
+}
+
+// Escape text for XML, dropping the control characters that XML cannot contain.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() && c < ' ' => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// A writer for ZIP archives with the files stored uncompressed. The timestamps are fixed so the
+// same contents always give the same archive. ZIP64 is not supported, so an archive that would need
+// it is an error.
+#[derive(Default)]
+struct ZipWriter {
+    data: Vec<u8>,
+    entries: Vec<ZipEntry>,
+}
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+impl ZipWriter {
+    // The DOS date of 1980-01-01, the earliest a ZIP archive can record.
+    const DATE: u16 = 0x0021;
+
+    fn add(&mut self, name: &str, contents: &str) -> Result<()> {
+        let contents = contents.as_bytes();
+        let entry = ZipEntry {
+            name: name.into(),
+            crc: crc32(contents),
+            size: zip_field(contents.len(), "file size")?,
+            offset: zip_field(self.data.len(), "archive size")?,
+        };
+        self.data.extend_from_slice(&0x0403_4b50_u32.to_le_bytes());
+        self.header_fields(&entry)?;
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.extend_from_slice(contents);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // The fields shared by the local and central headers, from the version needed to extract
+    // through the length of the extra field.
+    fn header_fields(&mut self, entry: &ZipEntry) -> Result<()> {
+        let name_length: u16 = zip_field(entry.name.len(), "file name length")?;
+        // Version 2.0, with the UTF-8 names flag and no compression.
+        for field in [20_u16, 0x0800, 0, 0, Self::DATE] {
+            self.data.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [entry.crc, entry.size, entry.size] {
+            self.data.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [name_length, 0] {
+            self.data.extend_from_slice(&field.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        let directory_offset: u32 = zip_field(self.data.len(), "archive size")?;
+        let entries = take(&mut self.entries);
+        for entry in &entries {
+            self.data.extend_from_slice(&0x0201_4b50_u32.to_le_bytes());
+            // Made by version 2.0.
+            self.data.extend_from_slice(&20_u16.to_le_bytes());
+            self.header_fields(entry)?;
+            // The comment length, disk number, and attributes, followed by the offset.
+            for field in [0_u16, 0, 0, 0, 0] {
+                self.data.extend_from_slice(&field.to_le_bytes());
+            }
+            self.data.extend_from_slice(&entry.offset.to_le_bytes());
+            self.data.extend_from_slice(entry.name.as_bytes());
+        }
+        let directory_size = zip_field::<u32>(self.data.len(), "archive size")? - directory_offset;
+        let count: u16 = zip_field(entries.len(), "number of files")?;
+        self.data.extend_from_slice(&0x0605_4b50_u32.to_le_bytes());
+        for field in [0_u16, 0, count, count] {
+            self.data.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in [directory_size, directory_offset] {
+            self.data.extend_from_slice(&field.to_le_bytes());
+        }
+        // No comment.
+        self.data.extend_from_slice(&0_u16.to_le_bytes());
+        Ok(self.data)
+    }
+}
+
+// Convert a size to the width of its ZIP header field, failing when it would need ZIP64.
+fn zip_field<T: TryFrom<usize>>(value: usize, what: &str) -> Result<T> {
+    T::try_from(value)
+        .ok()
+        .with_context(|| format!("The workbook is too large, with a {what} of {value}"))
+}
+
+// Compute the CRC-32 checksum used by ZIP archives.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn column_names() {
+        assert_eq!(column_name(0), "A");
+        assert_eq!(column_name(25), "Z");
+        assert_eq!(column_name(26), "AA");
+        assert_eq!(column_name(701), "ZZ");
+        assert_eq!(column_name(702), "AAA");
+    }
+
+    #[test]
+    fn formula_like_text_is_kept_as_text() {
+        let mut sheet = Sheet::default();
+        sheet.add_row(vec![
+            Cell::Text("=HYPERLINK(\"x\")".into()),
+            Cell::Text("plain".into()),
+        ]);
+        let (xml, rels) = sheet.to_xml();
+        assert!(xml.contains("<c r=\"A1\" s=\"3\" t=\"inlineStr\">"));
+        assert!(xml.contains("=HYPERLINK(&quot;x&quot;)"));
+        assert!(xml.contains("<c r=\"B1\" s=\"0\" t=\"inlineStr\">"));
+        assert!(!xml.contains("<f>"));
+        assert!(rels.is_none());
+    }
+
+    #[test]
+    fn oversized_fields_are_errors() {
+        assert!(zip_field::<u16>(usize::from(u16::MAX) + 1, "number of files").is_err());
+        assert_eq!(zip_field::<u16>(3, "number of files").unwrap(), 3);
+    }
+}